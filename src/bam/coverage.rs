@@ -0,0 +1,489 @@
+// Copyright 2017 Johannes Köster.
+// Licensed under the MIT license (http://opensource.org/licenses/MIT)
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Module for computing read depth, analogous to `samtools depth` and `mosdepth`.
+//!
+//! In contrast to counting alignments in pileups, depth is computed directly from the
+//! aligned blocks of the records, i.e. bases of matches (`M`, `=` and `X`), but not
+//! deletions or reference skips, are counted. Regions are processed in chunks, each of which
+//! is fetched via the index, such that areas without reads are skipped cheaply.
+//! Where the two mates of a pair overlap, bases are counted only once.
+//!
+//! Depth is yielded as runs of positions with equal depth (as in bedGraph), which can be
+//! expanded to single positions or summarized in windows.
+//!
+//! ```no_run
+//! use std::fs;
+//! use rust_htslib::bam;
+//! use rust_htslib::bam::coverage::CoverageBuilder;
+//!
+//! let mut bam = bam::IndexedReader::from_path(&"test/test.bam").unwrap();
+//! let mut builder = CoverageBuilder::new();
+//! builder.min_mapq(20);
+//!
+//! let mut out = fs::File::create("test/depth.bedgraph").unwrap();
+//! builder.depths(&mut bam).write_bedgraph(&mut out).unwrap();
+//!
+//! // mean depth and number of bases covered at least 10x and 30x in windows of 500bp
+//! for window in builder.region_depths(&mut bam, &[(0, 0, 10000)]).unwrap().windows(500, &[10, 30]) {
+//!     let window = window.unwrap();
+//!     println!("{}:{}-{} {} {:?}", window.tid, window.beg, window.end, window.mean, window.covered);
+//! }
+//! ```
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+
+use bam;
+use bam::Read;
+use bam::pileup::Filter;
+use bam::record::Record;
+
+
+/// Number of positions that are fetched and counted at once.
+const CHUNK_SIZE: u32 = 1 << 22;
+
+
+/// Builder for depth computations, with configurable record filters.
+pub struct CoverageBuilder {
+    filter: Filter<'static>,
+    overlaps: bool,
+}
+
+
+impl CoverageBuilder {
+    /// Create a new builder. By default, unmapped, secondary, QC-failed and duplicate
+    /// records are skipped and bases where mates overlap are counted once.
+    pub fn new() -> Self {
+        CoverageBuilder {
+            filter: Filter::new(),
+            overlaps: true,
+        }
+    }
+
+    /// Only use records that have all of the given flags set.
+    pub fn required_flags(&mut self, flags: u16) -> &mut Self {
+        self.filter.required_flags = flags;
+        self
+    }
+
+    /// Skip records that have any of the given flags set.
+    pub fn excluded_flags(&mut self, flags: u16) -> &mut Self {
+        self.filter.excluded_flags = flags;
+        self
+    }
+
+    /// Skip records with a mapping quality below the given value.
+    pub fn min_mapq(&mut self, mapq: u8) -> &mut Self {
+        self.filter.min_mapq = mapq;
+        self
+    }
+
+    /// Whether to count bases where the mates of a pair overlap only once (default) or
+    /// once per mate.
+    pub fn overlaps(&mut self, correct: bool) -> &mut Self {
+        self.overlaps = correct;
+        self
+    }
+
+    /// Depth over all targets of the given reader.
+    pub fn depths<'a>(&self, reader: &'a mut bam::IndexedReader) -> Depths<'a> {
+        let regions = (0..reader.header.target_count()).map(|tid| {
+            (tid, 0, reader.header.target_len(tid).unwrap())
+        }).filter(|&(_, _, len)| len > 0).collect();
+        self.build(reader, regions)
+    }
+
+    /// Depth over the given regions, given as `(tid, beg, end)` tuples (0-based, half-open).
+    /// Regions are processed in the given order, and clipped to the length of the target.
+    pub fn region_depths<'a>(
+        &self,
+        reader: &'a mut bam::IndexedReader,
+        regions: &[(u32, u32, u32)]
+    ) -> Result<Depths<'a>, CoverageError> {
+        let mut clipped = Vec::with_capacity(regions.len());
+        for &(tid, beg, end) in regions {
+            let len = match reader.header.target_len(tid) {
+                Some(len) => len,
+                None      => return Err(CoverageError::InvalidRegion(tid, beg, end))
+            };
+            let end = end.min(len);
+            if beg >= end {
+                return Err(CoverageError::InvalidRegion(tid, beg, end));
+            }
+            clipped.push((tid, beg, end));
+        }
+        Ok(self.build(reader, clipped))
+    }
+
+    fn build<'a>(&self, reader: &'a mut bam::IndexedReader, regions: Vec<(u32, u32, u32)>) -> Depths<'a> {
+        let names = reader.header.target_names().into_iter().map(|name| name.to_owned()).collect();
+        Depths {
+            reader: reader,
+            names: names,
+            filter: Filter {
+                required_flags: self.filter.required_flags,
+                excluded_flags: self.filter.excluded_flags,
+                min_mapq: self.filter.min_mapq,
+                closure: None,
+            },
+            overlaps: self.overlaps,
+            regions: regions,
+            region: 0,
+            next_beg: None,
+            counts: Vec::new(),
+            runs: VecDeque::new(),
+            record: Record::new(),
+        }
+    }
+}
+
+
+/// A run of positions (0-based, half-open) with equal depth.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DepthInterval {
+    pub tid: u32,
+    pub beg: u32,
+    pub end: u32,
+    pub depth: u32,
+}
+
+
+/// Depth at a single position.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BaseDepth {
+    pub tid: u32,
+    pub pos: u32,
+    pub depth: u32,
+}
+
+
+/// Mean depth of a window (0-based, half-open), together with the number of positions
+/// that have at least the depth of each threshold.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WindowCoverage {
+    pub tid: u32,
+    pub beg: u32,
+    pub end: u32,
+    pub mean: f64,
+    pub covered: Vec<u32>,
+}
+
+
+/// Iterator over the depth of a set of regions, as runs of positions with equal depth.
+/// Every position of the regions is covered, including those without reads.
+pub struct Depths<'a> {
+    reader: &'a mut bam::IndexedReader,
+    names: Vec<Vec<u8>>,
+    filter: Filter<'static>,
+    overlaps: bool,
+    regions: Vec<(u32, u32, u32)>,
+    region: usize,
+    next_beg: Option<u32>,
+    counts: Vec<i32>,
+    runs: VecDeque<(DepthInterval, usize)>,
+    record: Record,
+}
+
+
+impl<'a> Depths<'a> {
+    /// Expand the runs into the depth of single positions.
+    pub fn per_base(self) -> PerBaseDepths<'a> {
+        PerBaseDepths { depths: self, current: None }
+    }
+
+    /// Summarize the depth in windows of the given size. Windows start at the beginning
+    /// of each region, and the last window of a region is truncated at its end.
+    /// In order to obtain a single summary per region, use a size of `u32::MAX`.
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - the window size, has to be positive
+    /// * `thresholds` - depths for which to count the positions covered at least as deep
+    pub fn windows(self, size: u32, thresholds: &[u32]) -> Windows<'a> {
+        assert!(size > 0, "window size has to be positive");
+        Windows { depths: self, size: size, thresholds: thresholds.to_vec(), pending: None }
+    }
+
+    /// Write the depth in bedGraph format, i.e. target name, begin, end and depth.
+    pub fn write_bedgraph<W: io::Write>(mut self, out: &mut W) -> Result<(), CoverageError> {
+        while let Some(interval) = self.next() {
+            let interval = try!(interval);
+            try!(writeln!(
+                out, "{}\t{}\t{}\t{}",
+                String::from_utf8_lossy(&self.names[interval.tid as usize]),
+                interval.beg, interval.end, interval.depth
+            ));
+        }
+        Ok(())
+    }
+
+    /// Next run, together with the index of its region. Runs are merged across chunks.
+    fn next_run(&mut self) -> Option<Result<(DepthInterval, usize), CoverageError>> {
+        let (mut run, region) = match self.runs.pop_front() {
+            Some(run) => run,
+            None      => {
+                match self.fill() {
+                    Ok(true)  => self.runs.pop_front().unwrap(),
+                    Ok(false) => return None,
+                    Err(e)    => return Some(Err(e))
+                }
+            }
+        };
+        loop {
+            if self.runs.is_empty() && region == self.region {
+                if let Err(e) = self.fill() {
+                    return Some(Err(e));
+                }
+            }
+            match self.runs.front() {
+                Some(&(ref next, next_region)) if next_region == region && next.depth == run.depth => {
+                    run.end = next.end;
+                },
+                _ => return Some(Ok((run, region)))
+            }
+            self.runs.pop_front();
+        }
+    }
+
+    /// Count the next chunk. Returns false if all regions have been processed.
+    fn fill(&mut self) -> Result<bool, CoverageError> {
+        if self.region >= self.regions.len() {
+            return Ok(false);
+        }
+        let (tid, region_beg, region_end) = self.regions[self.region];
+        let beg = self.next_beg.unwrap_or(region_beg);
+        let end = if region_end - beg > CHUNK_SIZE { beg + CHUNK_SIZE } else { region_end };
+
+        let region = self.region;
+        if end == region_end {
+            self.region += 1;
+            self.next_beg = None;
+        } else {
+            self.next_beg = Some(end);
+        }
+
+        try!(self.reader.seek(tid, beg, end));
+        self.counts.clear();
+        let mut mates: HashMap<Vec<u8>, Vec<(u32, u32)>> = HashMap::new();
+        loop {
+            match self.reader.read(&mut self.record) {
+                Ok(())                            => (),
+                Err(bam::ReadError::NoMoreRecord) => break,
+                Err(e)                            => return Err(CoverageError::ReadError(e))
+            }
+            let record = &self.record;
+            if !self.filter.passes(record) || record.is_unmapped() {
+                continue;
+            }
+            if self.counts.is_empty() {
+                self.counts.resize((end - beg) as usize + 1, 0);
+            }
+
+            let blocks = record.aligned_blocks();
+            for &(block_beg, block_end) in &blocks {
+                add(&mut self.counts, beg, end, block_beg, block_end, 1);
+            }
+
+            if self.overlaps && is_overlap_candidate(record) {
+                if let Some(mate_blocks) = mates.remove(record.qname()) {
+                    // second mate, remove bases counted for the first one
+                    for &(a_beg, a_end) in &blocks {
+                        for &(b_beg, b_end) in &mate_blocks {
+                            add(&mut self.counts, beg, end, a_beg.max(b_beg), a_end.min(b_end), -1);
+                        }
+                    }
+                } else if let Some(&(_, last_end)) = blocks.last() {
+                    let mpos = record.mpos() as u32;
+                    if mpos >= record.pos() as u32 && mpos < last_end {
+                        mates.insert(record.qname().to_owned(), blocks);
+                    }
+                }
+            }
+        }
+
+        if self.counts.is_empty() {
+            // no reads, the index allowed to skip this chunk
+            self.runs.push_back((DepthInterval { tid: tid, beg: beg, end: end, depth: 0 }, region));
+            return Ok(true);
+        }
+        let mut depth = 0;
+        let mut run = DepthInterval { tid: tid, beg: beg, end: beg, depth: 0 };
+        for (pos, &count) in (beg..end).zip(self.counts.iter()) {
+            depth += count;
+            if depth as u32 != run.depth && pos > run.beg {
+                let next = DepthInterval { tid: tid, beg: pos, end: pos, depth: depth as u32 };
+                self.runs.push_back((run, region));
+                run = next;
+            }
+            run.depth = depth as u32;
+            run.end = pos + 1;
+        }
+        self.runs.push_back((run, region));
+        Ok(true)
+    }
+}
+
+
+impl<'a> Iterator for Depths<'a> {
+    type Item = Result<DepthInterval, CoverageError>;
+
+    fn next(&mut self) -> Option<Result<DepthInterval, CoverageError>> {
+        self.next_run().map(|run| run.map(|(interval, _)| interval))
+    }
+}
+
+
+/// Iterator over the depth of single positions.
+pub struct PerBaseDepths<'a> {
+    depths: Depths<'a>,
+    current: Option<DepthInterval>,
+}
+
+
+impl<'a> Iterator for PerBaseDepths<'a> {
+    type Item = Result<BaseDepth, CoverageError>;
+
+    fn next(&mut self) -> Option<Result<BaseDepth, CoverageError>> {
+        if self.current.as_ref().map_or(true, |interval| interval.beg >= interval.end) {
+            self.current = match self.depths.next() {
+                Some(Ok(interval)) => Some(interval),
+                Some(Err(e))       => return Some(Err(e)),
+                None               => return None
+            };
+        }
+        let interval = self.current.as_mut().unwrap();
+        interval.beg += 1;
+        Some(Ok(BaseDepth { tid: interval.tid, pos: interval.beg - 1, depth: interval.depth }))
+    }
+}
+
+
+/// Iterator over the depth of a set of regions, summarized in windows.
+pub struct Windows<'a> {
+    depths: Depths<'a>,
+    size: u32,
+    thresholds: Vec<u32>,
+    pending: Option<(DepthInterval, usize)>,
+}
+
+
+impl<'a> Windows<'a> {
+    /// Write the windows in BED format, i.e. target name, begin, end, mean depth and the
+    /// number of positions covered at least as deep as each threshold. A header line
+    /// (starting with `#`) names the columns.
+    pub fn write_bed<W: io::Write>(mut self, out: &mut W) -> Result<(), CoverageError> {
+        try!(write!(out, "#chrom\tstart\tend\tmean"));
+        for threshold in &self.thresholds {
+            try!(write!(out, "\t{}X", threshold));
+        }
+        try!(writeln!(out, ""));
+        while let Some(window) = self.next() {
+            let window = try!(window);
+            try!(write!(
+                out, "{}\t{}\t{}\t{:.2}",
+                String::from_utf8_lossy(&self.depths.names[window.tid as usize]),
+                window.beg, window.end, window.mean
+            ));
+            for covered in &window.covered {
+                try!(write!(out, "\t{}", covered));
+            }
+            try!(writeln!(out, ""));
+        }
+        Ok(())
+    }
+}
+
+
+impl<'a> Iterator for Windows<'a> {
+    type Item = Result<WindowCoverage, CoverageError>;
+
+    fn next(&mut self) -> Option<Result<WindowCoverage, CoverageError>> {
+        let (first, region) = match self.pending.take() {
+            Some(run) => run,
+            None      => match self.depths.next_run() {
+                Some(Ok(run)) => run,
+                Some(Err(e))  => return Some(Err(e)),
+                None          => return None
+            }
+        };
+        let (tid, region_beg, region_end) = self.depths.regions[region];
+        let beg = region_beg + (first.beg - region_beg) / self.size * self.size;
+        let end = if region_end - beg > self.size { beg + self.size } else { region_end };
+
+        let mut sum = 0u64;
+        let mut covered = vec![0; self.thresholds.len()];
+        let mut run = first;
+        loop {
+            let run_end = run.end.min(end);
+            let len = run_end - run.beg;
+            sum += len as u64 * run.depth as u64;
+            for (threshold, covered) in self.thresholds.iter().zip(covered.iter_mut()) {
+                if run.depth >= *threshold {
+                    *covered += len;
+                }
+            }
+            if run.end > end {
+                run.beg = end;
+                self.pending = Some((run, region));
+            }
+            if run_end == end {
+                break;
+            }
+            // runs cover all positions of a region, hence the window continues with the next run
+            run = match self.depths.next_run() {
+                Some(Ok((run, _))) => run,
+                Some(Err(e))       => return Some(Err(e)),
+                None               => break
+            };
+        }
+
+        Some(Ok(WindowCoverage {
+            tid: tid,
+            beg: beg,
+            end: end,
+            mean: sum as f64 / (end - beg) as f64,
+            covered: covered,
+        }))
+    }
+}
+
+
+/// Whether the mate of a record could overlap with it.
+fn is_overlap_candidate(record: &Record) -> bool {
+    record.is_paired() && !record.is_mate_unmapped() && !record.is_secondary() &&
+    !record.is_supplementary() && record.tid() == record.mtid()
+}
+
+
+/// Add the given value to the depth of an interval, clipped to the chunk.
+fn add(counts: &mut [i32], chunk_beg: u32, chunk_end: u32, beg: u32, end: u32, value: i32) {
+    let beg = beg.max(chunk_beg);
+    let end = end.min(chunk_end);
+    if beg < end {
+        counts[(beg - chunk_beg) as usize] += value;
+        counts[(end - chunk_beg) as usize] -= value;
+    }
+}
+
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum CoverageError {
+        ReadError(err: bam::ReadError) {
+            from()
+        }
+        SeekError(err: bam::SeekError) {
+            from()
+        }
+        InvalidRegion(tid: u32, beg: u32, end: u32) {
+            description("invalid region")
+            display("invalid region {}:{}-{}", tid, beg, end)
+        }
+        IOError(err: io::Error) {
+            from()
+        }
+    }
+}
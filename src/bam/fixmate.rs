@@ -0,0 +1,156 @@
+// Copyright 2017 Johannes Köster.
+// Licensed under the MIT license (http://opensource.org/licenses/MIT)
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Module for filling in mate information of read pairs, analogous to `samtools fixmate`.
+//!
+//! ```no_run
+//! use rust_htslib::bam;
+//! use rust_htslib::bam::Read;
+//! use rust_htslib::bam::fixmate::FixMates;
+//!
+//! let bam = bam::Reader::from_path(&"test/test_namesorted.bam").unwrap();
+//! let mut out = bam::Writer::from_path(&"test/fixed.bam", &bam::Header::from_template(bam.header())).unwrap();
+//! for record in FixMates::new(bam.records()) {
+//!     out.write(&record.unwrap()).unwrap();
+//! }
+//! ```
+
+use std::collections::VecDeque;
+
+use htslib;
+use bam;
+use bam::markdup;
+use bam::Record;
+use bam::record::Aux;
+use bam::template::{Templates, TemplateError};
+
+
+const PROPER_PAIR: u16 = 0x2;
+const MATE_UNMAPPED: u16 = 0x8;
+const MATE_REVERSE: u16 = 0x20;
+
+
+/// Fill in the mate information of two primary records of a read pair, i.e. mate target id
+/// and position, insert size, the mate reverse and mate unmapped flags and the `MC` (mate
+/// CIGAR), `MQ` (mate mapping quality) and `ms` (mate score, i.e. the sum of base qualities
+/// of at least 15, as used by `bam::markdup`) tags.
+/// Unmapped records are placed at the position of their mapped mate. The proper pair flag
+/// is cleared if one of the records is unmapped.
+pub fn fixmate(a: &mut Record, b: &mut Record) {
+    place_unmapped(a, b);
+    place_unmapped(b, a);
+    sync_mate(a, b);
+    sync_mate(b, a);
+
+    if a.tid() == b.tid() && !a.is_unmapped() && !b.is_unmapped() {
+        let a5 = five_prime(a);
+        let b5 = five_prime(b);
+        a.set_insert_size(b5 - a5);
+        b.set_insert_size(a5 - b5);
+    } else {
+        a.set_insert_size(0);
+        b.set_insert_size(0);
+    }
+
+    if a.is_unmapped() || b.is_unmapped() {
+        let (a_flags, b_flags) = (a.flags(), b.flags());
+        a.set_flags(a_flags & !PROPER_PAIR);
+        b.set_flags(b_flags & !PROPER_PAIR);
+    }
+}
+
+
+/// Give an unmapped record the position of its mapped mate.
+fn place_unmapped(src: &Record, dest: &mut Record) {
+    if dest.is_unmapped() && !src.is_unmapped() {
+        dest.set_tid(src.tid());
+        dest.set_pos(src.pos());
+    }
+}
+
+
+/// Update the mate information of `dest` from `src`.
+fn sync_mate(src: &Record, dest: &mut Record) {
+    dest.set_mtid(src.tid());
+    dest.set_mpos(src.pos());
+    let mut flags = dest.flags() & !(MATE_REVERSE | MATE_UNMAPPED);
+    if src.is_reverse() {
+        flags |= MATE_REVERSE;
+    }
+    if src.is_unmapped() {
+        flags |= MATE_UNMAPPED;
+    }
+    dest.set_flags(flags);
+
+    dest.remove_aux(b"MC");
+    dest.remove_aux(b"MQ");
+    dest.remove_aux(b"ms");
+    dest.push_aux(b"ms", &Aux::Integer(markdup::score(src) as i32));
+    if !src.is_unmapped() {
+        let cigar = src.cigar();
+        if !cigar.is_empty() {
+            dest.push_aux(b"MC", &Aux::String(cigar.to_string().as_bytes()));
+        }
+        dest.push_aux(b"MQ", &Aux::Integer(src.mapq() as i32));
+    }
+}
+
+
+/// 5' position of a mapped record.
+fn five_prime(record: &Record) -> i32 {
+    if record.is_reverse() {
+        unsafe { htslib::bam_endpos(record.inner) }
+    } else {
+        record.pos()
+    }
+}
+
+
+/// Streaming adapter that fixes the mate information of records grouped by query name
+/// (e.g. sorted by query name or collated). Primary records of complete pairs are updated
+/// with `fixmate`, all other records are passed through unchanged, in the input order.
+pub struct FixMates<I: Iterator<Item=Result<Record, bam::ReadError>>> {
+    templates: Templates<I>,
+    buffer: VecDeque<Record>,
+}
+
+
+impl<I: Iterator<Item=Result<Record, bam::ReadError>>> FixMates<I> {
+    /// Create a new adapter over the given records.
+    pub fn new(records: I) -> Self {
+        FixMates { templates: Templates::new(records).allow_incomplete(), buffer: VecDeque::new() }
+    }
+}
+
+
+impl<I: Iterator<Item=Result<Record, bam::ReadError>>> Iterator for FixMates<I> {
+    type Item = Result<Record, TemplateError>;
+
+    fn next(&mut self) -> Option<Result<Record, TemplateError>> {
+        if let Some(record) = self.buffer.pop_front() {
+            return Some(Ok(record));
+        }
+        let mut template = match self.templates.next() {
+            Some(Ok(template)) => template,
+            Some(Err(e))       => return Some(Err(e)),
+            None               => return None
+        };
+
+        let primary = |rec: &Record, first: bool| {
+            rec.is_paired() && !rec.is_secondary() && !rec.is_supplementary() &&
+            rec.is_first_in_template() == first && rec.is_last_in_template() != first
+        };
+        let i = template.records.iter().position(|rec| primary(rec, true));
+        let j = template.records.iter().position(|rec| primary(rec, false));
+        if let (Some(i), Some(j)) = (i, j) {
+            let (lo, hi) = (i.min(j), i.max(j));
+            let (left, right) = template.records.split_at_mut(hi);
+            fixmate(&mut left[lo], &mut right[0]);
+        }
+
+        self.buffer.extend(template.records);
+        self.buffer.pop_front().map(Ok)
+    }
+}
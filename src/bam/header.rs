@@ -22,6 +22,16 @@ impl Header {
         Header { records: vec![header.as_bytes().to_owned()] }
     }
 
+    /// Create a header from SAM header text, with one record per line.
+    pub fn from_bytes(text: &[u8]) -> Self {
+        Header {
+            records: text.split(|&c| c == b'\n')
+                         .filter(|line| !line.is_empty())
+                         .map(|line| line.to_owned())
+                         .collect()
+        }
+    }
+
     /// Add a record to the header.
     pub fn push_record(&mut self, record: &HeaderRecord) -> &mut Self {
         self.records.push(record.to_bytes());
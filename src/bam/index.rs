@@ -0,0 +1,71 @@
+// Copyright 2017 Johannes Köster.
+// Licensed under the MIT license (http://opensource.org/licenses/MIT)
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Module for building BAM indices.
+//!
+//! ```no_run
+//! use rust_htslib::bam::index;
+//!
+//! index::build(&"test/test.bam", index::IndexType::Bai).unwrap();
+//! ```
+//!
+//! Indices can also be built while writing a coordinate-sorted BAM file,
+//! see `bam::Writer::build_index`.
+
+use std::path::Path;
+
+use htslib;
+use utils;
+
+
+/// Type of a BAM index.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IndexType {
+    /// BAI index, as created by `samtools index`.
+    Bai,
+    /// CSI index with the given minimal interval size (as a power of 2, samtools uses 14).
+    /// CSI indices are required for contigs longer than 2^29 bp.
+    Csi { min_shift: u32 },
+}
+
+
+/// Build an index for the coordinate-sorted BAM file at the given path.
+/// The index is stored next to the BAM file, with extension `.bai` or `.csi`.
+///
+/// # Arguments
+///
+/// * `path` - the path to the BAM file
+/// * `idx_type` - the type of the index
+pub fn build<P: AsRef<Path>>(path: P, idx_type: IndexType) -> Result<(), BuildError> {
+    let min_shift = match idx_type {
+        IndexType::Bai => 0,
+        IndexType::Csi { min_shift } => min_shift as i32,
+    };
+    match utils::path_to_cstring(&path) {
+        Some(p) if path.as_ref().exists() => {
+            match unsafe { htslib::bam_index_build(p.as_ptr(), min_shift) } {
+                0 => Ok(()),
+                _ => Err(BuildError::Some)
+            }
+        },
+        _ => Err(BuildError::InvalidPath)
+    }
+}
+
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum BuildError {
+        InvalidPath {
+            description("invalid path")
+        }
+        Unsupported {
+            description("index can only be built when writing to a path, before writing any record and without multi-threading")
+        }
+        Some {
+            description("error building index")
+        }
+    }
+}
@@ -0,0 +1,567 @@
+// Copyright 2017 Johannes Köster.
+// Licensed under the MIT license (http://opensource.org/licenses/MIT)
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Module for marking PCR and optical duplicates in coordinate-sorted input, following the
+//! criteria of Picard MarkDuplicates.
+//!
+//! Reads are considered duplicates if they originate from the same library and share the
+//! unclipped 5' position and orientation of both ends (for pairs) or of the single end
+//! (for fragments). Of each set of duplicates, the template with the highest sum of base
+//! qualities (of at least 15) is kept. Fragments are marked as duplicates if a pair has an
+//! end at the same position. Optionally, a UMI tag is taken into account.
+//!
+//! Since the mate of a read might come much later in coordinate-sorted input, the position
+//! of the mate is obtained from the `MC` (mate CIGAR) tag, as e.g. written by
+//! `bam::fixmate`. The score of the mate is taken from the `ms` tag if present.
+//! Secondary and supplementary records are passed through unchanged, i.e. they are not
+//! marked as duplicates even if the primary records of their template are.
+//!
+//! ```no_run
+//! use std::io;
+//! use rust_htslib::bam;
+//! use rust_htslib::bam::Read;
+//! use rust_htslib::bam::markdup::MarkDuplicates;
+//!
+//! let bam = bam::Reader::from_path(&"test/test.bam").unwrap();
+//! let mut out = bam::Writer::from_path(&"test/markdup.bam", &bam::Header::from_template(bam.header())).unwrap();
+//! let mut markdup = MarkDuplicates::new(bam.records(), bam.header()).umi_tag(b"RX");
+//! for record in markdup.by_ref() {
+//!     out.write(&record.unwrap()).unwrap();
+//! }
+//! markdup.write_metrics(&mut io::stdout()).unwrap();
+//! ```
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::str;
+
+use bam;
+use bam::{Record, HeaderView};
+use bam::record::{Aux, CigarString};
+
+
+/// Library name used for reads without read group or library, as in Picard.
+const UNKNOWN_LIBRARY: &'static [u8] = b"Unknown Library";
+const DUPLICATE: u16 = 0x400;
+const MIN_BASE_QUAL: u8 = 15;
+
+
+/// Target id, unclipped 5' position and whether the read is reverse.
+type End = (i32, i32, bool);
+/// Library, UMI and end of a fragment.
+type FragmentKey = (Vec<u8>, Vec<u8>, End);
+/// Library, UMI and both ends (in coordinate order) of a pair.
+type PairKey = (Vec<u8>, Vec<u8>, End, End);
+
+
+/// Duplication metrics of a library, as reported by Picard MarkDuplicates.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DuplicationMetrics {
+    pub unpaired_reads_examined: u64,
+    pub read_pairs_examined: u64,
+    pub secondary_or_supplementary_reads: u64,
+    pub unmapped_reads: u64,
+    pub unpaired_read_duplicates: u64,
+    pub read_pair_duplicates: u64,
+    pub read_pair_optical_duplicates: u64,
+}
+
+
+impl DuplicationMetrics {
+    /// Fraction of mapped reads that are marked as duplicates.
+    pub fn percent_duplication(&self) -> f64 {
+        let examined = self.unpaired_reads_examined + 2 * self.read_pairs_examined;
+        if examined == 0 {
+            0.0
+        } else {
+            (self.unpaired_read_duplicates + 2 * self.read_pair_duplicates) as f64 / examined as f64
+        }
+    }
+
+    /// Estimated number of unique molecules in the library, based on the read pairs
+    /// (as in Picard). Returns `None` if there are no duplicate pairs.
+    pub fn estimated_library_size(&self) -> Option<u64> {
+        let pairs = (self.read_pairs_examined - self.read_pair_optical_duplicates) as f64;
+        let unique = (self.read_pairs_examined - self.read_pair_duplicates) as f64;
+        // sampling the observed pairs from x molecules yields x * (1 - exp(-pairs / x)) unique ones
+        let f = |x: f64| unique / x - 1.0 + (-pairs / x).exp();
+
+        if pairs <= 0.0 || unique >= pairs || f(unique) < 0.0 {
+            return None;
+        }
+        let (mut lo, mut hi) = (1.0, 100.0);
+        while f(hi * unique) > 0.0 {
+            hi *= 10.0;
+        }
+        for _ in 0..40 {
+            let mid = (lo + hi) / 2.0;
+            let v = f(mid * unique);
+            if v == 0.0 {
+                break;
+            } else if v > 0.0 {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        Some((unique * (lo + hi) / 2.0) as u64)
+    }
+}
+
+
+/// Streaming adapter that marks duplicates in coordinate-sorted records.
+/// Records are returned in input order, with the duplicate flag set or cleared.
+pub struct MarkDuplicates<I: Iterator<Item=Result<Record, bam::ReadError>>> {
+    records: I,
+    libraries: HashMap<Vec<u8>, Vec<u8>>,
+    umi_tag: Option<Vec<u8>>,
+    optical_distance: i32,
+    buffer: VecDeque<Entry>,
+    window: i32,
+    last: Option<(i32, i32)>,
+    exhausted: bool,
+    next_id: u64,
+    duplicates: HashMap<u64, bool>,
+    mates: HashMap<Vec<u8>, u64>,
+    pairs: HashMap<PairKey, DupSet>,
+    fragments: HashMap<FragmentKey, DupSet>,
+    metrics: HashMap<Vec<u8>, DuplicationMetrics>,
+}
+
+
+impl<I: Iterator<Item=Result<Record, bam::ReadError>>> MarkDuplicates<I> {
+    /// Create a new adapter.
+    ///
+    /// # Arguments
+    ///
+    /// * `records` - coordinate-sorted records
+    /// * `header` - the header of the records, used to map read groups to libraries
+    pub fn new(records: I, header: &HeaderView) -> Self {
+        let mut libraries = HashMap::new();
+        for line in header.as_bytes().split(|&c| c == b'\n').filter(|line| line.starts_with(b"@RG")) {
+            let mut id = None;
+            let mut lb = None;
+            for field in line.split(|&c| c == b'\t') {
+                if field.starts_with(b"ID:") {
+                    id = Some(field[3..].to_owned());
+                } else if field.starts_with(b"LB:") {
+                    lb = Some(field[3..].to_owned());
+                }
+            }
+            if let (Some(id), Some(lb)) = (id, lb) {
+                libraries.insert(id, lb);
+            }
+        }
+
+        MarkDuplicates {
+            records: records,
+            libraries: libraries,
+            umi_tag: None,
+            optical_distance: 100,
+            buffer: VecDeque::new(),
+            window: 0,
+            last: None,
+            exhausted: false,
+            next_id: 0,
+            duplicates: HashMap::new(),
+            mates: HashMap::new(),
+            pairs: HashMap::new(),
+            fragments: HashMap::new(),
+            metrics: HashMap::new(),
+        }
+    }
+
+    /// Only consider reads as duplicates if they have the same value in the given tag
+    /// (e.g. `RX`), such that reads with different UMIs are kept.
+    pub fn umi_tag(mut self, tag: &[u8]) -> Self {
+        self.umi_tag = Some(tag.to_owned());
+        self
+    }
+
+    /// Maximum distance of clusters on the flowcell (parsed from Illumina read names)
+    /// for duplicate pairs to be counted as optical duplicates. Default is 100.
+    pub fn optical_distance(mut self, distance: u32) -> Self {
+        self.optical_distance = distance as i32;
+        self
+    }
+
+    /// Duplication metrics per library. The metrics are complete once all records
+    /// have been returned.
+    pub fn metrics(&self) -> &HashMap<Vec<u8>, DuplicationMetrics> {
+        &self.metrics
+    }
+
+    /// Write the metrics per library as tab-separated table with Picard's column names.
+    pub fn write_metrics<W: io::Write>(&self, out: &mut W) -> io::Result<()> {
+        try!(writeln!(
+            out,
+            "LIBRARY\tUNPAIRED_READS_EXAMINED\tREAD_PAIRS_EXAMINED\tSECONDARY_OR_SUPPLEMENTARY_RDS\t\
+             UNMAPPED_READS\tUNPAIRED_READ_DUPLICATES\tREAD_PAIR_DUPLICATES\t\
+             READ_PAIR_OPTICAL_DUPLICATES\tPERCENT_DUPLICATION\tESTIMATED_LIBRARY_SIZE"
+        ));
+        let mut libraries: Vec<_> = self.metrics.keys().collect();
+        libraries.sort();
+        for library in libraries {
+            let m = &self.metrics[library];
+            try!(writeln!(
+                out, "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                String::from_utf8_lossy(library),
+                m.unpaired_reads_examined,
+                m.read_pairs_examined,
+                m.secondary_or_supplementary_reads,
+                m.unmapped_reads,
+                m.unpaired_read_duplicates,
+                m.read_pair_duplicates,
+                m.read_pair_optical_duplicates,
+                m.percent_duplication(),
+                m.estimated_library_size().map_or(String::new(), |size| size.to_string())
+            ));
+        }
+        Ok(())
+    }
+
+    fn library(&self, record: &Record) -> Vec<u8> {
+        match record.aux(b"RG") {
+            Some(Aux::String(rg)) => self.libraries.get(rg).map_or(UNKNOWN_LIBRARY, |lb| &lb[..]).to_owned(),
+            _ => UNKNOWN_LIBRARY.to_owned()
+        }
+    }
+
+    fn new_template(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.duplicates.insert(id, false);
+        id
+    }
+
+    /// Register a record and put it into the buffer.
+    fn push(&mut self, mut record: Record) -> Result<(), MarkDupError> {
+        let library = self.library(&record);
+        let mut id = None;
+        let mut last_of_template = true;
+        let mut fragment = None;
+        let mut pair = None;
+
+        if record.is_secondary() || record.is_supplementary() {
+            self.metrics.entry(library).or_insert_with(Default::default).secondary_or_supplementary_reads += 1;
+        } else if record.is_unmapped() {
+            self.metrics.entry(library).or_insert_with(Default::default).unmapped_reads += 1;
+        } else {
+            let pos = (record.tid(), record.pos());
+            if self.last.map_or(false, |last| (pos.0 as u32, pos.1) < (last.0 as u32, last.1)) {
+                return Err(MarkDupError::Unsorted);
+            }
+            self.last = Some(pos);
+
+            let flags = record.flags();
+            record.set_flags(flags & !DUPLICATE);
+            let cigar = record.cigar();
+            let end = (record.tid(), five_prime(record.pos(), &cigar, record.is_reverse()), record.is_reverse());
+            let span = cigar.ref_len() as i32 + leading_clips(&cigar) + trailing_clips(&cigar);
+            if span > self.window {
+                self.window = span;
+            }
+            let umi = self.umi_tag.as_ref().and_then(|tag| match record.aux(tag) {
+                Some(Aux::String(umi)) => Some(umi.to_owned()),
+                _ => None
+            }).unwrap_or_else(Vec::new);
+            let fragment_key = (library.clone(), umi.clone(), end);
+
+            if record.is_paired() && !record.is_mate_unmapped() && record.mtid() >= 0 {
+                if let Some(template) = self.mates.remove(record.qname()) {
+                    // second read of a pair
+                    id = Some(template);
+                } else {
+                    let mate_cigar = match record.aux(b"MC") {
+                        Some(Aux::String(mc)) => str::from_utf8(mc).ok().and_then(|mc| mc.parse().ok()),
+                        _ => None
+                    };
+                    let mate_cigar = match mate_cigar {
+                        Some(cigar) => cigar,
+                        None => return Err(MarkDupError::MissingMateCigar(
+                            String::from_utf8_lossy(record.qname()).into_owned()
+                        ))
+                    };
+                    let mate_end = (
+                        record.mtid(),
+                        five_prime(record.mpos(), &mate_cigar, record.is_mate_reverse()),
+                        record.is_mate_reverse()
+                    );
+                    let key = if (end.0 as u32, end.1, end.2) <= (mate_end.0 as u32, mate_end.1, mate_end.2) {
+                        (library.clone(), umi, end, mate_end)
+                    } else {
+                        (library.clone(), umi, mate_end, end)
+                    };
+                    let mate_score = match record.aux(b"ms") {
+                        Some(Aux::Integer(score)) => score as i64,
+                        _ => 0
+                    };
+
+                    let template = self.new_template();
+                    self.mates.insert(record.qname().to_owned(), template);
+                    self.metrics.entry(library.clone()).or_insert_with(Default::default).read_pairs_examined += 1;
+                    let member = Member {
+                        id: template,
+                        score: score(&record) + mate_score,
+                        location: location(record.qname()),
+                        duplicate: false
+                    };
+                    let set = self.pairs.entry(key.clone()).or_insert_with(|| DupSet::new(library.clone()));
+                    set.push(member, &mut self.duplicates);
+                    id = Some(template);
+                    last_of_template = false;
+                    pair = Some(key);
+                }
+
+                // fragments at the position of either end of a pair are duplicates
+                let set = self.fragments.entry(fragment_key.clone()).or_insert_with(|| DupSet::new(library.clone()));
+                set.pending += 1;
+                if !set.has_pair {
+                    set.has_pair = true;
+                    set.best = None;
+                    for member in set.members.iter_mut() {
+                        member.duplicate = true;
+                        self.duplicates.insert(member.id, true);
+                    }
+                }
+                fragment = Some(fragment_key);
+            } else {
+                let template = self.new_template();
+                self.metrics.entry(library.clone()).or_insert_with(Default::default).unpaired_reads_examined += 1;
+                let member = Member { id: template, score: score(&record), location: None, duplicate: false };
+                let set = self.fragments.entry(fragment_key.clone()).or_insert_with(|| DupSet::new(library.clone()));
+                set.push(member, &mut self.duplicates);
+                id = Some(template);
+                fragment = Some(fragment_key);
+            }
+        }
+
+        self.buffer.push_back(Entry {
+            record: record,
+            id: id,
+            last_of_template: last_of_template,
+            fragment: fragment,
+            pair: pair
+        });
+        Ok(())
+    }
+
+    /// Whether the first buffered record can no longer become a duplicate of upcoming records.
+    fn front_done(&self) -> bool {
+        match self.buffer.front() {
+            Some(entry) => {
+                self.exhausted || (entry.fragment.is_none() && entry.pair.is_none()) ||
+                self.last.map_or(true, |(tid, pos)| {
+                    tid != entry.record.tid() || entry.record.pos() + self.window < pos
+                })
+            },
+            None => false
+        }
+    }
+
+    /// Remove the first buffered record and set its duplicate flag.
+    fn pop(&mut self) -> Record {
+        let mut entry = self.buffer.pop_front().unwrap();
+        if let Some(key) = entry.fragment.take() {
+            let done = {
+                let set = self.fragments.get_mut(&key).unwrap();
+                set.pending -= 1;
+                set.pending == 0
+            };
+            if done {
+                self.fragments.remove(&key);
+            }
+        }
+        if let Some(key) = entry.pair.take() {
+            let done = {
+                let set = self.pairs.get_mut(&key).unwrap();
+                set.pending -= 1;
+                set.pending == 0
+            };
+            if done {
+                let set = self.pairs.remove(&key).unwrap();
+                let optical = set.optical_duplicates(self.optical_distance);
+                self.metrics.entry(set.library).or_insert_with(Default::default).read_pair_optical_duplicates += optical;
+            }
+        }
+
+        let mut record = entry.record;
+        if let Some(id) = entry.id {
+            let duplicate = self.duplicates[&id];
+            if entry.last_of_template {
+                self.duplicates.remove(&id);
+            }
+            if duplicate {
+                record.set_duplicate();
+                let library = self.library(&record);
+                let metrics = self.metrics.entry(library).or_insert_with(Default::default);
+                if !record.is_paired() || record.is_mate_unmapped() || record.mtid() < 0 {
+                    metrics.unpaired_read_duplicates += 1;
+                } else if !entry.last_of_template {
+                    metrics.read_pair_duplicates += 1;
+                }
+            }
+        }
+        record
+    }
+}
+
+
+impl<I: Iterator<Item=Result<Record, bam::ReadError>>> Iterator for MarkDuplicates<I> {
+    type Item = Result<Record, MarkDupError>;
+
+    fn next(&mut self) -> Option<Result<Record, MarkDupError>> {
+        loop {
+            if self.front_done() {
+                return Some(Ok(self.pop()));
+            }
+            if self.exhausted {
+                return None;
+            }
+            match self.records.next() {
+                Some(Ok(record)) => if let Err(e) = self.push(record) {
+                    return Some(Err(e));
+                },
+                Some(Err(e)) => return Some(Err(MarkDupError::ReadError(e))),
+                None         => self.exhausted = true
+            }
+        }
+    }
+}
+
+
+/// A buffered record, with its template and the duplicate sets it belongs to.
+struct Entry {
+    record: Record,
+    id: Option<u64>,
+    last_of_template: bool,
+    fragment: Option<FragmentKey>,
+    pair: Option<PairKey>,
+}
+
+
+/// A template in a set of potential duplicates.
+struct Member {
+    id: u64,
+    score: i64,
+    location: Option<(Vec<u8>, i32, i32)>,
+    duplicate: bool,
+}
+
+
+/// Templates sharing the same key, of which only the best one is kept.
+struct DupSet {
+    library: Vec<u8>,
+    members: Vec<Member>,
+    best: Option<usize>,
+    has_pair: bool,
+    pending: usize,
+}
+
+
+impl DupSet {
+    fn new(library: Vec<u8>) -> Self {
+        DupSet { library: library, members: Vec::new(), best: None, has_pair: false, pending: 0 }
+    }
+
+    /// Add a template, marking it or the previously best template as duplicate.
+    fn push(&mut self, mut member: Member, duplicates: &mut HashMap<u64, bool>) {
+        self.pending += 1;
+        if self.has_pair {
+            member.duplicate = true;
+            duplicates.insert(member.id, true);
+        } else {
+            match self.best {
+                Some(best) if self.members[best].score >= member.score => {
+                    member.duplicate = true;
+                    duplicates.insert(member.id, true);
+                },
+                Some(best) => {
+                    self.members[best].duplicate = true;
+                    duplicates.insert(self.members[best].id, true);
+                    self.best = Some(self.members.len());
+                },
+                None => self.best = Some(self.members.len())
+            }
+        }
+        self.members.push(member);
+    }
+
+    /// Number of duplicates that are close to another template of the set on the flowcell.
+    fn optical_duplicates(&self, distance: i32) -> u64 {
+        self.members.iter().enumerate().filter(|&(i, member)| {
+            member.duplicate && match member.location {
+                Some((ref tile, x, y)) => self.members.iter().enumerate().any(|(j, other)| {
+                    i != j && match other.location {
+                        Some((ref other_tile, other_x, other_y)) => {
+                            tile == other_tile && (x - other_x).abs() <= distance && (y - other_y).abs() <= distance
+                        },
+                        None => false
+                    }
+                }),
+                None => false
+            }
+        }).count() as u64
+    }
+}
+
+
+/// Sum of base qualities of at least 15.
+pub(crate) fn score(record: &Record) -> i64 {
+    record.qual().iter().filter(|&&q| q >= MIN_BASE_QUAL).map(|&q| q as i64).sum()
+}
+
+
+/// Tile and coordinates of the cluster, parsed from an Illumina read name
+/// (with 5 or 7 colon-separated fields).
+fn location(qname: &[u8]) -> Option<(Vec<u8>, i32, i32)> {
+    let fields: Vec<&[u8]> = qname.split(|&c| c == b':').collect();
+    if fields.len() != 5 && fields.len() != 7 {
+        return None;
+    }
+    let n = fields.len();
+    let parse = |field: &[u8]| String::from_utf8_lossy(field).parse::<i32>().ok();
+    match (parse(fields[n - 2]), parse(fields[n - 1])) {
+        (Some(x), Some(y)) => Some((fields[n - 3].to_owned(), x, y)),
+        _ => None
+    }
+}
+
+
+/// Unclipped 5' position of an alignment.
+fn five_prime(pos: i32, cigar: &CigarString, reverse: bool) -> i32 {
+    if reverse {
+        pos + cigar.ref_len() as i32 - 1 + trailing_clips(cigar)
+    } else {
+        pos - leading_clips(cigar)
+    }
+}
+
+
+fn leading_clips(cigar: &CigarString) -> i32 {
+    (cigar.leading_hardclips() + cigar.leading_softclips()) as i32
+}
+
+
+fn trailing_clips(cigar: &CigarString) -> i32 {
+    (cigar.trailing_hardclips() + cigar.trailing_softclips()) as i32
+}
+
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum MarkDupError {
+        ReadError(err: bam::ReadError) {
+            from()
+        }
+        Unsorted {
+            description("input is not sorted by coordinate")
+        }
+        MissingMateCigar(qname: String) {
+            description("missing MC tag, run fixmate first")
+            display("missing MC tag for paired read {}, run fixmate first", qname)
+        }
+    }
+}
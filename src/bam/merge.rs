@@ -0,0 +1,431 @@
+// Copyright 2017 Johannes Köster.
+// Licensed under the MIT license (http://opensource.org/licenses/MIT)
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Module for merging sorted BAM files into a single sorted stream.
+//!
+//! The `@SQ` dictionaries of all inputs are reconciled into a common one, and target ids of
+//! records are translated accordingly. For coordinate-sorted inputs, the merged dictionary
+//! keeps the relative order of the sequences of every input (e.g. `chr2, chr3` and
+//! `chr1, chr2` are merged into `chr1, chr2, chr3`), otherwise sequences are ordered by
+//! their first occurrence.
+//! `@RG` and `@PG` records with the same ID but different content are made unique by
+//! appending a suffix, and the `RG` and `PG` tags of the affected records are rewritten.
+//!
+//! ```no_run
+//! use rust_htslib::bam;
+//! use rust_htslib::bam::merge::Merger;
+//! use rust_htslib::bam::sort::SortOrder;
+//!
+//! let readers = vec![
+//!     bam::Reader::from_path(&"a.bam").unwrap(),
+//!     bam::Reader::from_path(&"b.bam").unwrap()
+//! ];
+//! let merger = Merger::new(readers, SortOrder::Coordinate).unwrap();
+//! let mut out = bam::Writer::from_path(&"merged.bam", &merger.header()).unwrap();
+//! for r in merger.records() {
+//!     out.write(&r.unwrap()).unwrap();
+//! }
+//! ```
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, BTreeSet, HashMap, HashSet};
+use std::mem;
+use std::path::Path;
+
+use bam;
+use bam::{Read, Record, Header};
+use bam::record::Aux;
+use bam::sort::{self, SortOrder};
+
+
+/// Merges the records of several sorted readers.
+pub struct Merger<R: Read> {
+    readers: Vec<R>,
+    order: SortOrder,
+    header: Vec<u8>,
+    tids: Vec<Vec<i32>>,
+    rgs: Vec<HashMap<Vec<u8>, Vec<u8>>>,
+    pgs: Vec<HashMap<Vec<u8>, Vec<u8>>>,
+    heap: BinaryHeap<MergeItem>,
+}
+
+
+impl<R: Read> Merger<R> {
+    /// Create a new merger. All readers have to be sorted by the given order.
+    ///
+    /// # Arguments
+    ///
+    /// * `readers` - the readers to merge
+    /// * `order` - the sort order of the readers and the merged stream
+    pub fn new(readers: Vec<R>, order: SortOrder) -> Result<Self, MergeError> {
+        let mut hd = None;
+        let mut seqs: Vec<(Vec<u8>, u32, Vec<u8>)> = Vec::new();
+        let mut seq_idx: HashMap<Vec<u8>, usize> = HashMap::new();
+        let mut rg_lines = Vec::new();
+        let mut pg_lines = Vec::new();
+        let mut other_lines = Vec::new();
+        let mut seen_lines = HashSet::new();
+        let mut orders = Vec::with_capacity(readers.len());
+        let mut rgs = Vec::with_capacity(readers.len());
+        let mut pgs = Vec::with_capacity(readers.len());
+
+        for reader in &readers {
+            let header = reader.header();
+            let lines: Vec<&[u8]> = header.as_bytes()
+                                          .split(|&c| c == b'\n')
+                                          .filter(|line| !line.is_empty())
+                                          .collect();
+            if hd.is_none() {
+                hd = lines.iter().find(|line| line.starts_with(b"@HD")).map(|line| line.to_vec());
+            }
+
+            // collect the sequence dictionary, keeping sequences in the order of first occurrence
+            let sq_lines: HashMap<&[u8], &[u8]> = lines.iter()
+                                                      .filter(|line| line.starts_with(b"@SQ"))
+                                                      .filter_map(|line| tag(line, b"SN").map(|name| (name, *line)))
+                                                      .collect();
+            let mut local = Vec::with_capacity(header.target_count() as usize);
+            for (tid, name) in header.target_names().into_iter().enumerate() {
+                let len = header.target_len(tid as u32).unwrap();
+                if let Some(&j) = seq_idx.get(name) {
+                    if seqs[j].1 != len {
+                        return Err(MergeError::IncompatibleSequence(
+                            String::from_utf8_lossy(name).into_owned()
+                        ));
+                    }
+                    local.push(j);
+                } else {
+                    let line = sq_lines.get(name)
+                                       .map(|line| line.to_vec())
+                                       .unwrap_or_else(|| format!(
+                                           "@SQ\tSN:{}\tLN:{}", String::from_utf8_lossy(name), len
+                                       ).into_bytes());
+                    seq_idx.insert(name.to_owned(), seqs.len());
+                    local.push(seqs.len());
+                    seqs.push((name.to_owned(), len, line));
+                }
+            }
+            orders.push(local);
+
+            rgs.push(try!(unique_ids(&lines, b"@RG", &mut rg_lines)));
+            pgs.push(try!(unique_ids(&lines, b"@PG", &mut pg_lines)));
+            for line in lines {
+                if !(line.starts_with(b"@HD") || line.starts_with(b"@SQ") ||
+                     line.starts_with(b"@RG") || line.starts_with(b"@PG")) &&
+                   seen_lines.insert(line.to_owned()) {
+                    other_lines.push(line.to_owned());
+                }
+            }
+        }
+
+        // For coordinate order, the merged dictionary has to be consistent with the order of
+        // every input, i.e. a topological order of the constraints given by the inputs.
+        let merged_order = if order == SortOrder::Coordinate {
+            match topological_order(seqs.len(), &orders) {
+                Some(merged_order) => merged_order,
+                None => {
+                    // report the first input that introduces a cycle
+                    let i = (1..orders.len()).find(|&k| topological_order(seqs.len(), &orders[..k + 1]).is_none())
+                                             .unwrap_or(0);
+                    return Err(MergeError::IncompatibleOrder(i));
+                }
+            }
+        } else {
+            (0..seqs.len()).collect()
+        };
+        let mut rank = vec![0; seqs.len()];
+        for (tid, &j) in merged_order.iter().enumerate() {
+            rank[j] = tid as i32;
+        }
+        let tids = orders.iter().map(|local| local.iter().map(|&j| rank[j]).collect()).collect();
+
+        let mut text = Vec::new();
+        text.extend(hd);
+        text.extend(merged_order.iter().map(|&j| seqs[j].2.clone()));
+        text.extend(rg_lines);
+        text.extend(pg_lines);
+        text.extend(other_lines);
+
+        let mut merger = Merger {
+            readers: readers,
+            order: order,
+            header: text.join(&b'\n'),
+            tids: tids,
+            rgs: rgs,
+            pgs: pgs,
+            heap: BinaryHeap::new(),
+        };
+        for source in 0..merger.readers.len() {
+            let mut record = Record::new();
+            if try!(merger.read_from(source, &mut record)) {
+                merger.heap.push(MergeItem { record: record, source: source, order: order });
+            }
+        }
+        Ok(merger)
+    }
+
+    /// Header of the merged stream, with the `SO` tag set according to the sort order.
+    pub fn header(&self) -> Header {
+        sort::sorted_header(&self.header, self.order)
+    }
+
+    /// Read the next record of the merged stream into the given record.
+    /// Returns `ReadError::NoMoreRecord` once all readers are exhausted.
+    pub fn read(&mut self, record: &mut Record) -> Result<(), bam::ReadError> {
+        match self.heap.pop() {
+            Some(mut item) => {
+                mem::swap(&mut item.record, record);
+                if try!(self.read_from(item.source, &mut item.record)) {
+                    self.heap.push(item);
+                }
+                Ok(())
+            },
+            None => Err(bam::ReadError::NoMoreRecord)
+        }
+    }
+
+    /// Iterator over the records of the merged stream.
+    pub fn records(self) -> Records<R> {
+        Records { merger: self }
+    }
+
+    /// Read the next record of the given source and translate it into the merged header.
+    /// Returns false if the source is exhausted.
+    fn read_from(&self, source: usize, record: &mut Record) -> Result<bool, bam::ReadError> {
+        match self.readers[source].read(record) {
+            Ok(()) => (),
+            Err(bam::ReadError::NoMoreRecord) => return Ok(false),
+            Err(e) => return Err(e)
+        }
+        let tids = &self.tids[source];
+        let tid = record.tid();
+        if tid >= 0 {
+            record.set_tid(tids[tid as usize]);
+        }
+        let mtid = record.mtid();
+        if mtid >= 0 {
+            record.set_mtid(tids[mtid as usize]);
+        }
+        rename_tag(record, b"RG", &self.rgs[source]);
+        rename_tag(record, b"PG", &self.pgs[source]);
+        Ok(true)
+    }
+}
+
+
+/// Iterator over the records of a merger.
+pub struct Records<R: Read> {
+    merger: Merger<R>,
+}
+
+
+impl<R: Read> Iterator for Records<R> {
+    type Item = Result<Record, bam::ReadError>;
+
+    fn next(&mut self) -> Option<Result<Record, bam::ReadError>> {
+        let mut record = Record::new();
+        match self.merger.read(&mut record) {
+            Err(bam::ReadError::NoMoreRecord) => None,
+            Ok(())   => Some(Ok(record)),
+            Err(err) => Some(Err(err))
+        }
+    }
+}
+
+
+/// Merge the given sorted readers into a new BAM file.
+///
+/// # Arguments
+///
+/// * `readers` - the readers to merge
+/// * `order` - the sort order of the readers
+/// * `path` - the path of the merged BAM file
+pub fn merge<R: Read, P: AsRef<Path>>(readers: Vec<R>, order: SortOrder, path: P) -> Result<(), MergeError> {
+    let mut merger = try!(Merger::new(readers, order));
+    let mut writer = try!(bam::Writer::from_path(path, &merger.header()));
+    let mut record = Record::new();
+    loop {
+        match merger.read(&mut record) {
+            Ok(()) => try!(writer.write(&record)),
+            Err(bam::ReadError::NoMoreRecord) => return Ok(()),
+            Err(e) => return Err(MergeError::ReadError(e))
+        }
+    }
+}
+
+
+/// A record in the merge heap, originating from the given source.
+struct MergeItem {
+    record: Record,
+    source: usize,
+    order: SortOrder,
+}
+
+
+impl Ord for MergeItem {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // reversed, such that the max-heap yields the smallest record first,
+        // records from earlier sources first on ties
+        match self.order.compare(&self.record, &other.record) {
+            Ordering::Equal => other.source.cmp(&self.source),
+            ord => ord.reverse()
+        }
+    }
+}
+
+
+impl PartialOrd for MergeItem {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+
+impl PartialEq for MergeItem {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+
+impl Eq for MergeItem {}
+
+
+/// Order the given number of sequences such that the relative order of the sequences in each
+/// of the given orders is kept. Ties are resolved by the index of the sequences.
+/// Returns None if the orders contradict each other.
+fn topological_order(n: usize, orders: &[Vec<usize>]) -> Option<Vec<usize>> {
+    let mut succs = vec![Vec::new(); n];
+    let mut in_degree = vec![0; n];
+    for local in orders {
+        for w in local.windows(2) {
+            succs[w[0]].push(w[1]);
+            in_degree[w[1]] += 1;
+        }
+    }
+
+    let mut ready: BTreeSet<usize> = (0..n).filter(|&j| in_degree[j] == 0).collect();
+    let mut sorted = Vec::with_capacity(n);
+    while let Some(j) = ready.iter().next().cloned() {
+        ready.remove(&j);
+        sorted.push(j);
+        for &k in &succs[j] {
+            in_degree[k] -= 1;
+            if in_degree[k] == 0 {
+                ready.insert(k);
+            }
+        }
+    }
+    if sorted.len() == n {
+        Some(sorted)
+    } else {
+        None
+    }
+}
+
+
+/// Value of the given tag in a header line.
+fn tag<'a>(line: &'a [u8], name: &[u8]) -> Option<&'a [u8]> {
+    line.split(|&c| c == b'\t')
+        .find(|field| field.len() > 3 && &field[..2] == name && field[2] == b':')
+        .map(|field| &field[3..])
+}
+
+
+/// Replace the given tag in a header line.
+fn replace_tag(line: &[u8], name: &[u8], value: &[u8]) -> Vec<u8> {
+    line.split(|&c| c == b'\t')
+        .map(|field| {
+            if field.len() > 3 && &field[..2] == name && field[2] == b':' {
+                [&field[..3], value].concat()
+            } else {
+                field.to_owned()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(&b'\t')
+}
+
+
+/// Add the records of the given type to the merged lines, renaming IDs that clash with a
+/// different record of an earlier reader. Identical records are only kept once.
+/// Returns the renamed IDs. For `@PG` records, `PP` references are updated as well.
+fn unique_ids(lines: &[&[u8]], rec_type: &[u8], merged: &mut Vec<Vec<u8>>) -> Result<HashMap<Vec<u8>, Vec<u8>>, MergeError> {
+    let records: Vec<&[u8]> = lines.iter().cloned().filter(|line| line.starts_with(rec_type)).collect();
+    let taken = |merged: &Vec<Vec<u8>>, id: &[u8]| merged.iter().any(|line| tag(line, b"ID") == Some(id));
+
+    let mut renamed = HashMap::new();
+    for line in &records {
+        let id = match tag(line, b"ID") {
+            Some(id) => id,
+            None     => return Err(MergeError::MissingID(String::from_utf8_lossy(line).into_owned()))
+        };
+        if taken(merged, id) && !merged.iter().any(|l| l.as_slice() == *line) {
+            let new_id = (1..).map(|i| format!("{}-{}", String::from_utf8_lossy(id), i).into_bytes())
+                              .find(|new_id| !taken(merged, new_id) &&
+                                             !records.iter().any(|l| tag(l, b"ID") == Some(new_id)))
+                              .unwrap();
+            renamed.insert(id.to_owned(), new_id);
+        }
+    }
+
+    for line in records {
+        let mut line = line.to_owned();
+        for t in &[&b"ID"[..], b"PP"] {
+            let new_id = tag(&line, t).and_then(|id| renamed.get(id)).cloned();
+            if let Some(new_id) = new_id {
+                line = replace_tag(&line, t, &new_id);
+            }
+        }
+        if !merged.contains(&line) {
+            merged.push(line);
+        }
+    }
+    Ok(renamed)
+}
+
+
+/// Rewrite the given string tag of a record if its value has been renamed.
+fn rename_tag(record: &mut Record, name: &[u8], renamed: &HashMap<Vec<u8>, Vec<u8>>) {
+    if renamed.is_empty() {
+        return;
+    }
+    let new_id = match record.aux(name) {
+        Some(Aux::String(id)) => renamed.get(id).cloned(),
+        _ => None
+    };
+    if let Some(new_id) = new_id {
+        record.remove_aux(name);
+        record.push_aux(name, &Aux::String(&new_id));
+    }
+}
+
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum MergeError {
+        IncompatibleSequence(name: String) {
+            description("sequence occurs with different lengths in input headers")
+            display("sequence {} occurs with different lengths in input headers", name)
+        }
+        IncompatibleOrder(reader: usize) {
+            description("order of sequences in input header is incompatible with merged header")
+            display("order of sequences in header of input {} is incompatible with merged header", reader)
+        }
+        MissingID(line: String) {
+            description("header record without ID tag")
+            display("header record without ID tag: {}", line)
+        }
+        ReadError(err: bam::ReadError) {
+            from()
+        }
+        WriteError(err: bam::WriteError) {
+            from()
+        }
+        WriterPathError(err: bam::WriterPathError) {
+            from()
+        }
+    }
+}
@@ -7,21 +7,35 @@
 pub mod record;
 pub mod header;
 pub mod pileup;
+pub mod index;
+pub mod sort;
+pub mod merge;
+pub mod template;
+pub mod fixmate;
+pub mod markdup;
+pub mod coverage;
 
 use std::ffi;
 use std::ptr;
 use std::slice;
+use std::thread;
+use std::vec;
+use std::cell::RefCell;
+use std::sync::mpsc;
+use std::collections::HashMap;
+use std::fs;
 use std::path::Path;
 use url::Url;
 
 use htslib;
+use utils;
 
 pub use bam::record::Record;
 pub use bam::header::Header;
 
 
 /// A trait for a BAM reader with a read method.
-pub trait Read: Sized {
+pub trait Read {
     /// Read next BAM record into given record.
     /// Use this method in combination with a single allocated record to avoid the reallocations
     /// occurring with the iterator.
@@ -35,9 +49,9 @@ pub trait Read: Sized {
     /// Note that, while being convenient, this is less efficient than pre-allocating a
     /// `Record` and reading into it with the `read` method, since every iteration involves
     /// the allocation of a new `Record`.
-    fn records(&self) -> Records<Self>;
+    fn records(&self) -> Records<Self> where Self: Sized;
 
-    /// Iterator over pileups.
+    /// Pileups over the records of the reader, which is borrowed until they are dropped.
     fn pileup(&self) -> pileup::Pileups;
 
     /// Return the BGZF struct
@@ -50,14 +64,10 @@ pub trait Read: Sized {
 
 /// A BAM reader.
 pub struct Reader {
-    bgzf: *mut htslib::Struct_BGZF,
-    header: HeaderView,
+    inner: HtsReader,
 }
 
 
-unsafe impl Send for Reader {}
-
-
 impl Reader {
     /// Create a new Reader from path.
     ///
@@ -91,26 +101,27 @@ impl Reader {
     ///
     /// * `path` - the path to open. Use "-" for stdin.
     fn new(path: &[u8]) -> Result<Self, BGZFError> {
-        let bgzf = try!(bgzf_open(&ffi::CString::new(path).unwrap(), b"r"));
-        let header = unsafe { htslib::bam_hdr_read(bgzf) };
-        Ok(Reader { bgzf: bgzf, header: HeaderView::new(header) })
+        let inner = try!(HtsReader::new(path, &[Format::BAM]).map_err(|_| BGZFError::Some));
+        Ok(Reader { inner: inner })
     }
 
-    extern fn pileup_read(data: *mut ::libc::c_void, record: *mut htslib::bam1_t) -> ::libc::c_int {
-        let _self = unsafe { &*(data as *mut Self) };
-        unsafe { htslib::bam_read1(_self.bgzf, record) }
+    /// Decompress and parse records in the background. This should permit faster
+    /// reading of large BAM files.
+    /// The bundled htslib only supports multi-threaded BGZF compression, hence a single
+    /// background thread reads ahead of the consumer instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `n_threads` - number of background threads to use (at least one)
+    pub fn set_threads(&mut self, n_threads: usize) -> Result<(), ThreadingError> {
+        self.inner.set_threads(n_threads)
     }
 }
 
 
 impl Read for Reader {
     fn read(&self, record: &mut record::Record) -> Result<(), ReadError> {
-        match unsafe { htslib::bam_read1(self.bgzf, record.inner) } {
-            -1 => Err(ReadError::NoMoreRecord),
-            -2 => Err(ReadError::Truncated),
-            -4 => Err(ReadError::Invalid),
-            _  => Ok(())
-        }
+        self.inner.read(record)
     }
 
     /// Iterator over the records of the seeked region.
@@ -122,40 +133,26 @@ impl Read for Reader {
     }
 
     fn pileup(&self) -> pileup::Pileups {
-        let _self = self as *const Self;
-        let itr = unsafe {
-            htslib::bam_plp_init(
-                Some(Reader::pileup_read),
-                _self as *mut ::libc::c_void
-            )
-        };
-        pileup::Pileups::new(itr)
+        pileup::Pileups::new(self)
     }
 
     fn bgzf(&self) -> *mut htslib::Struct_BGZF {
-        self.bgzf
+        self.inner.bgzf()
     }
 
     fn header(&self) -> &HeaderView {
-        &self.header
-    }
-}
-
-
-impl Drop for Reader {
-    fn drop(&mut self) {
-        unsafe {
-            htslib::bgzf_close(self.bgzf);
-        }
+        &self.inner.header
     }
 }
 
 
 pub struct IndexedReader {
-    bgzf: *mut htslib::Struct_BGZF,
+    htsfile: *mut htslib::htsFile,
     pub header: HeaderView,
     idx: *mut htslib::hts_idx_t,
     itr: Option<*mut htslib:: hts_itr_t>,
+    threaded: bool,
+    prefetch: RefCell<Option<Prefetch>>,
 }
 
 
@@ -172,7 +169,28 @@ impl IndexedReader {
     pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, IndexedReaderPathError> {
         match path.as_ref().to_str() {
             Some(p) if path.as_ref().exists() => {
-                Ok(try!(Self::new(&ffi::CString::new(p).unwrap())))
+                Ok(try!(Self::new(&ffi::CString::new(p).unwrap(), None)))
+            },
+            _ => {
+                Err(IndexedReaderPathError::InvalidPath)
+            }
+        }
+    }
+
+    /// Create a new Reader from path, using the index at the given path instead of
+    /// the one next to the BAM file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - the path to open.
+    /// * `index_path` - the path to the index. Has to end with `.bai`, `.csi` or `.crai`.
+    pub fn from_path_and_index<P: AsRef<Path>, I: AsRef<Path>>(path: P, index_path: I) -> Result<Self, IndexedReaderPathError> {
+        match (path.as_ref().to_str(), index_path.as_ref().to_str()) {
+            (Some(p), Some(i)) if path.as_ref().exists() => {
+                Ok(try!(Self::new(
+                    &ffi::CString::new(p).unwrap(),
+                    Some(&ffi::CString::new(i).unwrap())
+                )))
             },
             _ => {
                 Err(IndexedReaderPathError::InvalidPath)
@@ -181,7 +199,20 @@ impl IndexedReader {
     }
 
     pub fn from_url(url: &Url) -> Result<Self, IndexedReaderError> {
-        Self::new(&ffi::CString::new(url.as_str()).unwrap())
+        Self::new(&ffi::CString::new(url.as_str()).unwrap(), None)
+    }
+
+    /// Create a new Reader from URL, using the index at the given URL.
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - the URL to open.
+    /// * `index_url` - the URL of the index. Has to end with `.bai`, `.csi` or `.crai`.
+    pub fn from_url_and_index(url: &Url, index_url: &Url) -> Result<Self, IndexedReaderError> {
+        Self::new(
+            &ffi::CString::new(url.as_str()).unwrap(),
+            Some(&ffi::CString::new(index_url.as_str()).unwrap())
+        )
     }
 
     /// Create a new Reader.
@@ -189,44 +220,327 @@ impl IndexedReader {
     /// # Arguments
     ///
     /// * `path` - the path. Use "-" for stdin.
-    fn new(path: &ffi::CStr) -> Result<Self, IndexedReaderError> {
-        let bgzf = try!(bgzf_open(path, b"r"));
-        let header = unsafe { htslib::bam_hdr_read(bgzf) };
-        let idx = unsafe {
-            htslib::hts_idx_load(
-                path.as_ptr(),
-                htslib::HTS_FMT_BAI
-            )
+    /// * `index_path` - optional explicit path to the index.
+    fn new(path: &ffi::CStr, index_path: Option<&ffi::CStr>) -> Result<Self, IndexedReaderError> {
+        let htsfile = try!(hts_open(path, b"r").map_err(|_| BGZFError::Some));
+        let header = unsafe { htslib::sam_hdr_read(htsfile) };
+        if header.is_null() {
+            unsafe { htslib::hts_close(htsfile); }
+            return Err(IndexedReaderError::HTSError(HTSError::InvalidHeader));
+        }
+        let idx = match index_path {
+            // BAI, CSI (preferred if present) or CRAI next to the data file
+            None        => unsafe { htslib::sam_index_load(htsfile, path.as_ptr()) },
+            Some(index) => index_load(htsfile, index)
         };
         if idx.is_null() {
+            unsafe {
+                htslib::bam_hdr_destroy(header);
+                htslib::hts_close(htsfile);
+            }
             Err(IndexedReaderError::InvalidIndex)
         } else {
-            Ok(IndexedReader { bgzf: bgzf, header : HeaderView::new(header), idx: idx, itr: None })
+            Ok(IndexedReader {
+                htsfile: htsfile,
+                header : HeaderView::new(header),
+                idx: idx,
+                itr: None,
+                threaded: false,
+                prefetch: RefCell::new(None)
+            })
         }
     }
 
-    pub fn seek(&mut self, tid: u32, beg: u32, end: u32) -> Result<(), SeekError> {
-        if let Some(itr) = self.itr {
-            unsafe { htslib::hts_itr_destroy(itr) }
+    /// Decompress and parse records in the background. This should permit faster
+    /// reading of large BAM files. CRAM files are decoded with multiple threads by htslib.
+    /// For BAM files, the bundled htslib only supports multi-threaded BGZF compression,
+    /// hence a single background thread reads ahead of the consumer instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `n_threads` - number of background threads to use (at least one)
+    pub fn set_threads(&mut self, n_threads: usize) -> Result<(), ThreadingError> {
+        if n_threads == 0 {
+            return Err(ThreadingError::Some);
+        }
+        if hts_format(self.htsfile).format == htslib::cram {
+            return hts_set_threads(self.htsfile, n_threads);
         }
+        self.threaded = true;
+        Ok(())
+    }
+
+    pub fn seek(&mut self, tid: u32, beg: u32, end: u32) -> Result<(), SeekError> {
         let itr = unsafe {
             htslib::sam_itr_queryi(self.idx, tid as i32, beg as i32, end as i32)
         };
-        if itr.is_null() {
-            self.itr = None;
+        if self.set_itr(itr) {
+            Ok(())
+        } else {
             Err(SeekError::Some)
         }
-        else {
-            self.itr = Some(itr);
+    }
+
+    /// Fetch the unmapped reads without coordinate, which are stored at the end of a
+    /// coordinate-sorted BAM file.
+    pub fn fetch_unplaced(&mut self) -> Result<(), SeekError> {
+        let itr = unsafe {
+            htslib::sam_itr_queryi(self.idx, htslib::HTS_IDX_NOCOOR, 0, 0)
+        };
+        if self.set_itr(itr) {
+            Ok(())
+        } else {
+            Err(SeekError::Some)
+        }
+    }
+
+    /// Number of unmapped reads without coordinate, as recorded in the index.
+    pub fn unplaced_count(&self) -> u64 {
+        unsafe { htslib::hts_idx_get_n_no_coor(self.idx) }
+    }
+
+    /// Per-contig counts of mapped and unmapped reads, as stored in the index
+    /// (analogous to `samtools idxstats`). This does not require to read the BAM file.
+    /// Contigs without reads in the index are reported with zero counts. The number of
+    /// unmapped reads without coordinate (the `*` row of `samtools idxstats`) is given by
+    /// `unplaced_count`. CRAM indices do not provide these statistics, hence an
+    /// `IndexedReaderError::Unsupported` error is returned for CRAM files.
+    pub fn idxstats(&self) -> Result<Vec<IndexStats>, IndexedReaderError> {
+        if hts_format(self.htsfile).format == htslib::cram {
+            return Err(IndexedReaderError::Unsupported);
+        }
+
+        let names = self.header.target_names();
+        Ok((0..self.header.target_count()).map(|tid| {
+            let (mut mapped, mut unmapped) = (0, 0);
+            // contigs without reads have no statistics in the index
+            if unsafe { htslib::hts_idx_get_stat(self.idx, tid as i32, &mut mapped, &mut unmapped) } < 0 {
+                mapped = 0;
+                unmapped = 0;
+            }
+            IndexStats {
+                tid: tid,
+                name: names[tid as usize].to_owned(),
+                len: self.header.target_len(tid).unwrap(),
+                mapped: mapped,
+                unmapped: unmapped
+            }
+        }).collect())
+    }
+
+    /// Fetch the records of a region given as samtools-style region string.
+    ///
+    /// # Arguments
+    ///
+    /// * `region` - the region, e.g. `chr1:1,000-2,000` (1-based, inclusive), `chr1` for a whole
+    ///   contig, or `*` for the unmapped reads without coordinate.
+    pub fn fetch(&mut self, region: &[u8]) -> Result<(), FetchError> {
+        let cregion = try!(ffi::CString::new(region).map_err(
+            |_| FetchError::InvalidRegion(String::from_utf8_lossy(region).into_owned())
+        ));
+        if region != b"*" {
+            let invalid_region = || FetchError::InvalidRegion(String::from_utf8_lossy(region).into_owned());
+            let (mut beg, mut end) = (0, 0);
+            let name_end = unsafe { htslib::hts_parse_reg(cregion.as_ptr(), &mut beg, &mut end) };
+            if name_end.is_null() {
+                return Err(invalid_region());
+            }
+            let contig = &region[..name_end as usize - cregion.as_ptr() as usize];
+            if self.header.tid(region).is_none() {
+                if contig.len() < region.len() {
+                    // explicit interval
+                    if beg >= end {
+                        return Err(invalid_region());
+                    }
+                    if self.header.tid(contig).is_none() {
+                        return Err(FetchError::UnknownContig(String::from_utf8_lossy(contig).into_owned()));
+                    }
+                } else if region.contains(&b':') {
+                    // htslib falls back to interpreting malformed intervals as contig names
+                    return Err(invalid_region());
+                } else {
+                    return Err(FetchError::UnknownContig(String::from_utf8_lossy(contig).into_owned()));
+                }
+            }
+        }
+
+        let itr = unsafe {
+            htslib::sam_itr_querys(self.idx, self.header.inner, cregion.as_ptr())
+        };
+        if self.set_itr(itr) {
             Ok(())
+        } else {
+            Err(FetchError::Some)
+        }
+    }
+
+    /// Iterate over the records of multiple regions, given as `(tid, beg, end)` tuples
+    /// (0-based, half-open). Overlapping or adjacent regions are merged, such that each
+    /// record is visited only once, and records are returned in coordinate order.
+    /// All regions are queried up front, such that invalid regions are reported here
+    /// instead of while iterating. This replaces the current iterator.
+    ///
+    /// # Arguments
+    ///
+    /// * `regions` - the regions to fetch, in arbitrary order
+    pub fn fetch_regions(&mut self, regions: &[(u32, u32, u32)]) -> Result<RegionRecords, SeekError> {
+        if regions.iter().any(|&(tid, beg, end)| tid >= self.header.target_count() || beg >= end) {
+            return Err(SeekError::Some);
+        }
+
+        let mut sorted = regions.to_vec();
+        sorted.sort();
+        let mut merged: Vec<(u32, u32, u32)> = Vec::with_capacity(sorted.len());
+        for (tid, beg, end) in sorted {
+            if let Some(last) = merged.last_mut() {
+                if last.0 == tid && beg <= last.2 {
+                    if end > last.2 {
+                        last.2 = end;
+                    }
+                    continue;
+                }
+            }
+            merged.push((tid, beg, end));
+        }
+
+        let mut itrs = Vec::with_capacity(merged.len());
+        for &(tid, beg, end) in &merged {
+            let itr = unsafe {
+                htslib::sam_itr_queryi(self.idx, tid as i32, beg as i32, end as i32)
+            };
+            if itr.is_null() {
+                for itr in itrs {
+                    unsafe { htslib::hts_itr_destroy(itr) };
+                }
+                return Err(SeekError::Some);
+            }
+            itrs.push(itr);
+        }
+        // reading via the region iterators invalidates the current one
+        self.set_itr(ptr::null_mut());
+
+        Ok(RegionRecords { reader: self, regions: merged, itrs: itrs, i: 0, prev: None })
+    }
+
+    /// Pileups of the given region (0-based, half-open). In contrast to
+    /// calling `pileup` after `seek`, positions outside of the region are skipped.
+    ///
+    /// # Arguments
+    ///
+    /// * `tid` - the target id
+    /// * `beg` - the first position
+    /// * `end` - the position after the last one
+    pub fn pileup_region(&mut self, tid: u32, beg: u32, end: u32) -> Result<pileup::Pileups, SeekError> {
+        try!(self.seek(tid, beg, end));
+        let mut pileups = self.pileup();
+        pileups.set_region(tid, beg, end, false);
+        Ok(pileups)
+    }
+
+    /// Fetch the primary record of the mate of the given record, by seeking to the mate
+    /// position and matching query name and first/last in template flags.
+    /// Returns `None` if the record is unpaired, the mate has no position or cannot be found.
+    /// This replaces the current iterator.
+    ///
+    /// # Arguments
+    ///
+    /// * `record` - the record to find the mate for
+    pub fn fetch_mate(&mut self, record: &record::Record) -> Result<Option<record::Record>, FetchMateError> {
+        if !record.is_paired() || record.mtid() < 0 {
+            return Ok(None);
+        }
+        try!(self.seek(record.mtid() as u32, record.mpos() as u32, record.mpos() as u32 + 1));
+        let mut mate = record::Record::new();
+        loop {
+            match self.read(&mut mate) {
+                Ok(()) => if is_mate(record, &mate) {
+                    return Ok(Some(mate));
+                },
+                Err(ReadError::NoMoreRecord) => return Ok(None),
+                Err(e) => return Err(FetchMateError::ReadError(e))
+            }
+        }
+    }
+
+    /// Fetch the mates of many records (see `fetch_mate`). Lookups are grouped by locus,
+    /// such that mates that are close to each other are found with a single index query.
+    /// Returns the mates in the order of the given records. This replaces the current iterator.
+    ///
+    /// # Arguments
+    ///
+    /// * `records` - the records to find the mates for
+    pub fn fetch_mates(&mut self, records: &[record::Record]) -> Result<Vec<Option<record::Record>>, FetchMateError> {
+        let mut mates: Vec<Option<record::Record>> = records.iter().map(|_| None).collect();
+        let mut lookups: Vec<usize> = (0..records.len()).filter(|&i| {
+            records[i].is_paired() && records[i].mtid() >= 0
+        }).collect();
+        lookups.sort_by_key(|&i| (records[i].mtid(), records[i].mpos()));
+
+        let mut start = 0;
+        while start < lookups.len() {
+            // group mate positions on the same contig that are close to each other
+            let tid = records[lookups[start]].mtid();
+            let mut end = start + 1;
+            while end < lookups.len() && records[lookups[end]].mtid() == tid &&
+                  records[lookups[end]].mpos() - records[lookups[end - 1]].mpos() <= MATE_BATCH_GAP {
+                end += 1;
+            }
+            let group = &lookups[start..end];
+            start = end;
+
+            // pending lookups by mate position and whether the mate is the last segment
+            let mut pending: HashMap<(i32, bool), Vec<usize>> = HashMap::new();
+            for &i in group {
+                let rec = &records[i];
+                pending.entry((rec.mpos(), rec.is_first_in_template())).or_insert_with(Vec::new).push(i);
+            }
+            let beg = records[group[0]].mpos() as u32;
+            let last = records[group[group.len() - 1]].mpos() as u32;
+            try!(self.seek(tid as u32, beg, last + 1));
+
+            let mut mate = record::Record::new();
+            while !pending.is_empty() {
+                match self.read(&mut mate) {
+                    Ok(()) => {
+                        let key = (mate.pos(), mate.is_last_in_template());
+                        let done = match pending.get_mut(&key) {
+                            Some(lookups) => {
+                                lookups.retain(|&i| if is_mate(&records[i], &mate) {
+                                    mates[i] = Some(mate.clone());
+                                    false
+                                } else {
+                                    true
+                                });
+                                lookups.is_empty()
+                            },
+                            None => false
+                        };
+                        if done {
+                            pending.remove(&key);
+                        }
+                    },
+                    Err(ReadError::NoMoreRecord) => break,
+                    Err(e) => return Err(FetchMateError::ReadError(e))
+                }
+            }
         }
+        Ok(mates)
     }
 
-    extern fn pileup_read(data: *mut ::libc::c_void, record: *mut htslib::bam1_t) -> ::libc::c_int {
-        let _self = unsafe { &*(data as *mut Self) };
-        match _self.itr {
-            Some(itr) => itr_next(_self.bgzf, itr, record),
-            None      => 0
+    /// Replace the current iterator. Returns false if the given iterator is invalid.
+    fn set_itr(&mut self, itr: *mut htslib::hts_itr_t) -> bool {
+        // stop reading ahead from the current iterator
+        self.prefetch.get_mut().take();
+        if let Some(itr) = self.itr {
+            unsafe { htslib::hts_itr_destroy(itr) }
+        }
+        if itr.is_null() {
+            self.itr = None;
+            false
+        }
+        else {
+            self.itr = Some(itr);
+            true
         }
     }
 }
@@ -234,13 +548,17 @@ impl IndexedReader {
 
 impl Read for IndexedReader {
     fn read(&self, record: &mut record::Record) -> Result<(), ReadError> {
+        record.clear_cigar_cache();
         match self.itr {
-            Some(itr) => match itr_next(self.bgzf, itr, record.inner) {
-                -1 => Err(ReadError::NoMoreRecord),
-                -2 => Err(ReadError::Truncated),
-                -4 => Err(ReadError::Invalid),
-                _  => Ok(())
+            Some(itr) if self.threaded => {
+                let mut prefetch = self.prefetch.borrow_mut();
+                if prefetch.is_none() {
+                    let (htsfile, itr) = (SendPtr(self.htsfile), SendPtr(itr));
+                    *prefetch = Some(Prefetch::spawn(move |record| itr_read(htsfile.0, itr.0, record)));
+                }
+                prefetch.as_mut().unwrap().read(record)
             },
+            Some(itr) => itr_read(self.htsfile, itr, record.inner),
             None      => Err(ReadError::NoMoreRecord)
         }
     }
@@ -254,18 +572,12 @@ impl Read for IndexedReader {
     }
 
     fn pileup(&self) -> pileup::Pileups {
-        let _self = self as *const Self;
-        let itr = unsafe {
-            htslib::bam_plp_init(
-                Some(IndexedReader::pileup_read),
-                _self as *mut ::libc::c_void
-            )
-        };
-        pileup::Pileups::new(itr)
+        pileup::Pileups::new(self)
     }
 
+    /// Returns a null pointer if the input is CRAM.
     fn bgzf(&self) -> *mut htslib::Struct_BGZF {
-        self.bgzf
+        htsfile_bgzf(self.htsfile)
     }
 
     fn header(&self) -> &HeaderView {
@@ -276,473 +588,2659 @@ impl Read for IndexedReader {
 
 impl Drop for IndexedReader {
     fn drop(&mut self) {
+        self.prefetch.get_mut().take();
         unsafe {
             if self.itr.is_some() {
                 htslib::hts_itr_destroy(self.itr.unwrap());
             }
             htslib::hts_idx_destroy(self.idx);
-            htslib::bgzf_close(self.bgzf);
+            htslib::hts_close(self.htsfile);
         }
     }
 }
 
 
-/// A BAM writer.
-pub struct Writer {
-    f: *mut htslib::Struct_BGZF,
+/// Reader core shared by the readers that are based on htslib's generic file API.
+struct HtsReader {
+    htsfile: *mut htslib::htsFile,
     header: HeaderView,
+    prefetch: RefCell<Option<Prefetch>>,
 }
 
 
-unsafe impl Send for Writer {}
+unsafe impl Send for HtsReader {}
 
 
-impl Writer {
-    /// Create a new BAM file.
+impl HtsReader {
+    /// Open the given path for reading, as long as the detected format is among the given ones.
     ///
     /// # Arguments
     ///
-    /// * `path` - the path.
-    /// * `header` - header definition to use
-    pub fn from_path<P: AsRef<Path>>(path: P, header: &header::Header) -> Result<Self, WriterPathError> {
-        if let Some(p) = path.as_ref().to_str() {
-                Ok(try!(Self::new(p.as_bytes(), header)))
-        } else {
-            Err(WriterPathError::InvalidPath)
+    /// * `path` - the path to open.
+    /// * `formats` - the accepted formats.
+    fn from_path<P: AsRef<Path>>(path: P, formats: &[Format]) -> Result<Self, ReaderPathError> {
+        match path.as_ref().to_str() {
+            Some(p) if path.as_ref().exists() => {
+                Ok(try!(Self::new(p.as_bytes(), formats)))
+            },
+            _ => {
+                Err(ReaderPathError::InvalidPath)
+            }
         }
     }
 
-    /// Create a new BAM file at STDOUT.
-    ///
-    /// # Arguments
-    ///
-    /// * `header` - header definition to use
-    pub fn from_stdout(header: &header::Header) -> Result<Self, BGZFError> {
-        Self::new(b"-", header)
-    }
-
-    /// Create a new BAM file.
-    ///
-    /// # Arguments
+    /// Open the given path for reading and read the header.
     ///
-    /// * `path` - the path. Use "-" for stdin.
-    /// * `header` - header definition to use
-    fn new(path: &[u8], header: &header::Header) -> Result<Self, BGZFError> {
-        let f = try!(bgzf_open(&ffi::CString::new(path).unwrap(), b"w"));
-
-        // sam_hdr_parse does not populate the text and l_text fields of the header_record.
-        // This causes non-SQ headers to be dropped in the output BAM file.
-        // To avoid this, we copy the full header to a new C-string that is allocated with malloc,
-        // and set this into header_record manually.
-        let header_record = unsafe {
-            let header_string = header.to_bytes();
-
-            let l_text = header_string.len();
-            let text = ::libc::malloc(l_text + 1);
-            ::libc::memset(text, 0, l_text + 1);
-            ::libc::memcpy(text, header_string.as_ptr() as *const ::libc::c_void, header_string.len());
-
-            //println!("{}", str::from_utf8(&header_string).unwrap());
-            let rec = htslib::sam_hdr_parse(
-                (l_text + 1) as i32,
-                text as *const i8,
-            );
-
-            (*rec).text = text as *mut i8;
-            (*rec).l_text = l_text as u32;
-            rec
-        };
-
-        unsafe { htslib::bam_hdr_write(f, header_record); }
-
-        Ok(Writer { f: f, header: HeaderView::new(header_record) })
-    }
-
-    /// Activate multi-threaded BAM write support in htslib. This should permit faster
-    /// writing of large BAM files.
     /// # Arguments
     ///
-    /// * `n_threads` - number of background writer threads to use
-    pub fn set_threads(&mut self, n_threads: usize) -> Result<(), ThreadingError> {
-        let r = unsafe { htslib::bgzf_mt(self.f, n_threads as ::libc::c_int, 256) };
-        if r != 0 {
-            Err(ThreadingError::Some)
-        } else {
-            Ok(())
+    /// * `path` - the path to open. Use "-" for stdin.
+    /// * `formats` - the accepted formats.
+    fn new(path: &[u8], formats: &[Format]) -> Result<Self, HTSError> {
+        let htsfile = try!(hts_open(&ffi::CString::new(path).unwrap(), b"r"));
+        if !formats.contains(&Format::from_hts(hts_format(htsfile))) {
+            unsafe { htslib::hts_close(htsfile); }
+            return Err(HTSError::UnsupportedFormat);
+        }
+        let header = unsafe { htslib::sam_hdr_read(htsfile) };
+        if header.is_null() {
+            unsafe { htslib::hts_close(htsfile); }
+            return Err(HTSError::InvalidHeader);
         }
+        Ok(HtsReader { htsfile: htsfile, header: HeaderView::new(header), prefetch: RefCell::new(None) })
     }
 
-    /// Write record to BAM.
-    ///
-    /// # Arguments
-    ///
-    /// * `record` - the record to write
-    pub fn write(&mut self, record: &record::Record) -> Result<(), WriteError> {
-        if unsafe { htslib::bam_write1(self.f, record.inner) } == -1 {
-            Err(WriteError::Some)
+    /// CRAM files are decoded with multiple threads by htslib. For other formats, the bundled
+    /// htslib only supports multi-threaded compression, hence a single background thread
+    /// reads ahead instead.
+    fn set_threads(&mut self, n_threads: usize) -> Result<(), ThreadingError> {
+        if n_threads == 0 {
+            return Err(ThreadingError::Some);
         }
-        else {
-            Ok(())
+        if hts_format(self.htsfile).format == htslib::cram {
+            return hts_set_threads(self.htsfile, n_threads);
         }
+        let prefetch = self.prefetch.get_mut();
+        if prefetch.is_none() {
+            let (htsfile, header) = (SendPtr(self.htsfile), SendPtr(self.header.inner));
+            *prefetch = Some(Prefetch::spawn(move |record| sam_read(htsfile.0, header.0, record)));
+        }
+        Ok(())
     }
 
+    fn read(&self, record: &mut record::Record) -> Result<(), ReadError> {
+        record.clear_cigar_cache();
+        match *self.prefetch.borrow_mut() {
+            Some(ref mut prefetch) => prefetch.read(record),
+            None => sam_read(self.htsfile, self.header.inner, record.inner)
+        }
+    }
 
-    /// Return the header.
-    pub fn header(&self) -> &HeaderView {
-        &self.header
+    /// Returns a null pointer if the input is CRAM.
+    fn bgzf(&self) -> *mut htslib::Struct_BGZF {
+        htsfile_bgzf(self.htsfile)
     }
 }
 
 
-impl Drop for Writer {
+impl Drop for HtsReader {
     fn drop(&mut self) {
+        self.prefetch.get_mut().take();
         unsafe {
-            htslib::bgzf_close(self.f);
+            htslib::hts_close(self.htsfile);
         }
     }
 }
 
 
-/// Iterator over the records of a BAM.
-pub struct Records<'a, R: 'a + Read> {
-    reader: &'a R
-}
-
+/// Formats that can be read via htslib's generic file API.
+const ALIGNMENT_FORMATS: &'static [Format] = &[Format::SAM, Format::BAM, Format::CRAM];
 
-impl<'a, R: Read> Iterator for Records<'a, R> {
-    type Item = Result<record::Record, ReadError>;
 
-    fn next(&mut self) -> Option<Result<record::Record, ReadError>> {
-        let mut record = record::Record::new();
-        match self.reader.read(&mut record) {
-            Err(ReadError::NoMoreRecord) => None,
-            Ok(())   => Some(Ok(record)),
-            Err(err) => Some(Err(err))
-        }
-    }
+/// A SAM reader.
+pub struct SamReader {
+    inner: HtsReader,
 }
 
 
-quick_error! {
-    #[derive(Debug)]
-    pub enum ReadError {
-        Truncated {
-            description("truncated record")
-        }
-        Invalid {
-            description("invalid record")
-        }
-        NoMoreRecord {
-            description("no more record")
-        }
+impl SamReader {
+    /// Create a new SamReader from path.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - the path to open.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, ReaderPathError> {
+        Ok(SamReader { inner: try!(HtsReader::from_path(path, ALIGNMENT_FORMATS)) })
     }
-}
 
-
-impl ReadError {
-    /// Returns true if no record has been read because the end of the file was reached.
-    pub fn is_eof(&self) -> bool {
-        match self {
-            &ReadError::NoMoreRecord => true,
-            _ => false
-        }
+    /// Create a new SamReader from STDIN.
+    pub fn from_stdin() -> Result<Self, HTSError> {
+        Ok(SamReader { inner: try!(HtsReader::new(b"-", ALIGNMENT_FORMATS)) })
     }
-}
 
+    /// Create a new SamReader from URL.
+    pub fn from_url(url: &Url) -> Result<Self, HTSError> {
+        Ok(SamReader { inner: try!(HtsReader::new(url.as_str().as_bytes(), ALIGNMENT_FORMATS)) })
+    }
 
-quick_error! {
-    #[derive(Debug)]
-    pub enum IndexedReaderError {
-        InvalidIndex {
-            description("invalid index")
-        }
-        BGZFError(err: BGZFError) {
-            from()
-        }
+    /// Decompress and parse records in the background. This should permit faster
+    /// reading of large SAM files. With the bundled htslib, a single background thread
+    /// reads ahead of the consumer.
+    ///
+    /// # Arguments
+    ///
+    /// * `n_threads` - number of background threads to use (at least one)
+    pub fn set_threads(&mut self, n_threads: usize) -> Result<(), ThreadingError> {
+        self.inner.set_threads(n_threads)
     }
 }
 
 
-quick_error! {
-    #[derive(Debug)]
-    pub enum WriterPathError {
-        InvalidPath {
-            description("invalid path")
-        }
-        BGZFError(err: BGZFError) {
-            from()
-        }
+impl Read for SamReader {
+    fn read(&self, record: &mut record::Record) -> Result<(), ReadError> {
+        self.inner.read(record)
+    }
+
+    /// Iterator over the records of the SAM file.
+    /// Note that, while being convenient, this is less efficient than pre-allocating a
+    /// `Record` and reading into it with the `read` method, since every iteration involves
+    /// the allocation of a new `Record`.
+    fn records(&self) -> Records<Self> {
+        Records { reader: self }
+    }
+
+    fn pileup(&self) -> pileup::Pileups {
+        pileup::Pileups::new(self)
+    }
+
+    fn bgzf(&self) -> *mut htslib::Struct_BGZF {
+        self.inner.bgzf()
+    }
+
+    fn header(&self) -> &HeaderView {
+        &self.inner.header
     }
 }
 
 
-quick_error! {
-    #[derive(Debug)]
-    pub enum IndexedReaderPathError {
-        InvalidPath {
-            description("invalid path")
-        }
-        IndexedReaderError(err: IndexedReaderError) {
-            from()
-        }
+/// A CRAM reader.
+pub struct CramReader {
+    inner: HtsReader,
+}
+
+
+impl CramReader {
+    /// Create a new CramReader from path.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - the path to open.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, ReaderPathError> {
+        Ok(CramReader { inner: try!(HtsReader::from_path(path, ALIGNMENT_FORMATS)) })
+    }
+
+    /// Create a new CramReader from STDIN.
+    pub fn from_stdin() -> Result<Self, HTSError> {
+        Ok(CramReader { inner: try!(HtsReader::new(b"-", ALIGNMENT_FORMATS)) })
+    }
+
+    /// Create a new CramReader from URL.
+    pub fn from_url(url: &Url) -> Result<Self, HTSError> {
+        Ok(CramReader { inner: try!(HtsReader::new(url.as_str().as_bytes(), ALIGNMENT_FORMATS)) })
+    }
+
+    /// Set the reference FASTA used to decode the CRAM records.
+    /// The FASTA has to be indexed with `samtools faidx`.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - the path to the reference FASTA.
+    pub fn set_reference<P: AsRef<Path>>(&mut self, path: P) -> Result<(), CRAMError> {
+        cram_set_reference(self.inner.htsfile, path)
+    }
+
+    /// Set a CRAM option.
+    ///
+    /// # Arguments
+    ///
+    /// * `option` - the option to set
+    /// * `value` - the value to set
+    pub fn set_option(&mut self, option: CramOption, value: i32) -> Result<(), CRAMError> {
+        cram_set_option(self.inner.htsfile, option, value)
+    }
+
+    /// Activate multi-threaded decoding in htslib. This should permit faster
+    /// reading of large CRAM files.
+    ///
+    /// # Arguments
+    ///
+    /// * `n_threads` - number of background decoding threads to use
+    pub fn set_threads(&mut self, n_threads: usize) -> Result<(), ThreadingError> {
+        self.inner.set_threads(n_threads)
     }
 }
 
 
-quick_error! {
-    #[derive(Debug)]
-    pub enum BGZFError {
-        Some {
-            description("error reading BGZF file")
-        }
+impl Read for CramReader {
+    fn read(&self, record: &mut record::Record) -> Result<(), ReadError> {
+        self.inner.read(record)
+    }
+
+    /// Iterator over the records of the CRAM file.
+    /// Note that, while being convenient, this is less efficient than pre-allocating a
+    /// `Record` and reading into it with the `read` method, since every iteration involves
+    /// the allocation of a new `Record`.
+    fn records(&self) -> Records<Self> {
+        Records { reader: self }
+    }
+
+    fn pileup(&self) -> pileup::Pileups {
+        pileup::Pileups::new(self)
+    }
+
+    /// CRAM files are not BGZF compressed, hence this always returns a null pointer.
+    fn bgzf(&self) -> *mut htslib::Struct_BGZF {
+        ptr::null_mut()
+    }
+
+    fn header(&self) -> &HeaderView {
+        &self.inner.header
     }
 }
 
 
-quick_error! {
-    #[derive(Debug)]
-    pub enum ReaderPathError {
-        InvalidPath {
-            description("invalid path")
-        }
-        BGZFError(err: BGZFError) {
-            from()
+/// A reader that detects whether the input is SAM, BAM or CRAM and reads it transparently.
+pub struct AnyReader {
+    inner: HtsReader,
+}
+
+
+impl AnyReader {
+    /// Create a new AnyReader from path.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - the path to open.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, ReaderPathError> {
+        Ok(AnyReader { inner: try!(HtsReader::from_path(path, ALIGNMENT_FORMATS)) })
+    }
+
+    /// Create a new AnyReader from STDIN.
+    pub fn from_stdin() -> Result<Self, HTSError> {
+        Ok(AnyReader { inner: try!(HtsReader::new(b"-", ALIGNMENT_FORMATS)) })
+    }
+
+    /// Create a new AnyReader from URL.
+    pub fn from_url(url: &Url) -> Result<Self, HTSError> {
+        Ok(AnyReader { inner: try!(HtsReader::new(url.as_str().as_bytes(), ALIGNMENT_FORMATS)) })
+    }
+
+    fn hts_format(&self) -> &htslib::htsFormat {
+        hts_format(self.inner.htsfile)
+    }
+
+    /// The detected file format.
+    pub fn format(&self) -> Format {
+        Format::from_hts(self.hts_format())
+    }
+
+    /// The detected compression.
+    pub fn compression(&self) -> Compression {
+        Compression::from_hts(self.hts_format())
+    }
+
+    /// A human readable description of the detected format, e.g. "BAM version 1 compressed sequence data".
+    pub fn format_description(&self) -> String {
+        unsafe {
+            let desc = htslib::hts_format_description(self.hts_format());
+            let ret = String::from_utf8_lossy(ffi::CStr::from_ptr(desc).to_bytes()).into_owned();
+            ::libc::free(desc as *mut ::libc::c_void);
+            ret
         }
     }
+
+    /// Set the reference FASTA used to decode CRAM records.
+    /// This has no effect if the input is not CRAM.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - the path to the reference FASTA.
+    pub fn set_reference<P: AsRef<Path>>(&mut self, path: P) -> Result<(), CRAMError> {
+        cram_set_reference(self.inner.htsfile, path)
+    }
+
+    /// Decompress and parse records in the background. This should permit faster
+    /// reading of large SAM, BAM or CRAM files. CRAM files are decoded with multiple
+    /// threads by htslib. For SAM and BAM files, the bundled htslib only supports
+    /// multi-threaded compression, hence a single background thread reads ahead instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `n_threads` - number of background threads to use (at least one)
+    pub fn set_threads(&mut self, n_threads: usize) -> Result<(), ThreadingError> {
+        self.inner.set_threads(n_threads)
+    }
 }
 
 
-quick_error! {
-    #[derive(Debug)]
-    pub enum ThreadingError {
-        Some {
-            description("error setting threads for multi-threaded writing")
-        }
+impl Read for AnyReader {
+    fn read(&self, record: &mut record::Record) -> Result<(), ReadError> {
+        self.inner.read(record)
+    }
+
+    /// Iterator over the records of the file.
+    /// Note that, while being convenient, this is less efficient than pre-allocating a
+    /// `Record` and reading into it with the `read` method, since every iteration involves
+    /// the allocation of a new `Record`.
+    fn records(&self) -> Records<Self> {
+        Records { reader: self }
+    }
+
+    fn pileup(&self) -> pileup::Pileups {
+        pileup::Pileups::new(self)
+    }
+
+    /// Returns a null pointer if the input is CRAM.
+    fn bgzf(&self) -> *mut htslib::Struct_BGZF {
+        self.inner.bgzf()
+    }
+
+    fn header(&self) -> &HeaderView {
+        &self.inner.header
     }
 }
 
-quick_error! {
-    #[derive(Debug)]
-    pub enum WriteError {
-        Some {
-            description("error writing record")
+
+/// Number of records that are handed over at once by the background reading thread.
+const PREFETCH_BATCH_SIZE: usize = 1024;
+
+
+/// Number of batches that the background reading thread reads ahead.
+const PREFETCH_BATCHES: usize = 4;
+
+
+/// Records that are read ahead by a background thread, which thereby takes over
+/// decompressing and parsing them.
+struct Prefetch {
+    batches: Option<mpsc::Receiver<Result<Vec<record::Record>, ReadError>>>,
+    batch: vec::IntoIter<record::Record>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+
+impl Prefetch {
+    /// Start reading records in a background thread with the given function.
+    fn spawn<F>(mut read: F) -> Self
+        where F: 'static + Send + FnMut(*mut htslib::bam1_t) -> Result<(), ReadError>
+    {
+        let (sender, receiver) = mpsc::sync_channel(PREFETCH_BATCHES);
+        let thread = thread::spawn(move || {
+            loop {
+                let mut batch = Vec::with_capacity(PREFETCH_BATCH_SIZE);
+                let mut res = Ok(());
+                while batch.len() < PREFETCH_BATCH_SIZE {
+                    let record = record::Record::new();
+                    res = read(record.inner);
+                    if res.is_err() {
+                        break;
+                    }
+                    batch.push(record);
+                }
+                // sending fails if the reader has been dropped or the iterator replaced
+                if !batch.is_empty() && sender.send(Ok(batch)).is_err() {
+                    return;
+                }
+                match res {
+                    Ok(()) => (),
+                    Err(ReadError::NoMoreRecord) => return,
+                    Err(e) => {
+                        sender.send(Err(e)).ok();
+                        return;
+                    }
+                }
+            }
+        });
+        Prefetch { batches: Some(receiver), batch: Vec::new().into_iter(), thread: Some(thread) }
+    }
+
+    fn read(&mut self, record: &mut record::Record) -> Result<(), ReadError> {
+        loop {
+            if let Some(next) = self.batch.next() {
+                unsafe { htslib::bam_copy1(record.inner, next.inner) };
+                return Ok(());
+            }
+            match self.batches.as_ref().unwrap().recv() {
+                Ok(Ok(batch)) => self.batch = batch.into_iter(),
+                Ok(Err(e))    => return Err(e),
+                // the thread has read all records
+                Err(_)        => return Err(ReadError::NoMoreRecord)
+            }
         }
     }
 }
 
 
-quick_error! {
-    #[derive(Debug)]
-    pub enum SeekError {
-        Some {
-            description("error seeking to locus")
+impl Drop for Prefetch {
+    fn drop(&mut self) {
+        // disconnect first, such that the thread stops after its current batch
+        self.batches.take();
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
         }
     }
 }
 
 
-/// Wrapper for opening a BAM file.
-fn bgzf_open(path: &ffi::CStr, mode: &[u8]) -> Result<*mut htslib::Struct_BGZF, BGZFError> {
-    let ret = unsafe {
-        htslib::bgzf_open(
-            path.as_ptr(),
-            ffi::CString::new(mode).unwrap().as_ptr()
-        )
-    };
-    if ret.is_null() {
-        Err(BGZFError::Some)
-    } else {
-        Ok(ret)
+/// A raw pointer that is handed over to the background reading thread.
+struct SendPtr<T>(*mut T);
+
+
+unsafe impl<T> Send for SendPtr<T> {}
+
+
+/// Alignment file formats.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    SAM,
+    BAM,
+    CRAM,
+    Other,
+}
+
+
+impl Format {
+    fn from_hts(format: &htslib::htsFormat) -> Self {
+        match format.format {
+            htslib::sam  => Format::SAM,
+            htslib::bam  => Format::BAM,
+            htslib::cram => Format::CRAM,
+            _            => Format::Other,
+        }
     }
 }
 
 
-/// Wrapper for iterating an indexed BAM file.
-fn itr_next(bgzf: *mut htslib::Struct_BGZF, itr: *mut htslib:: hts_itr_t, record: *mut htslib::bam1_t) -> i32 {
-    unsafe {
-        htslib::hts_itr_next(
-            bgzf,
-            itr,
-            record as *mut ::libc::c_void,
-            ptr::null_mut()
-        )
+/// Compression of a file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    BGZF,
+    Custom,
+}
+
+
+impl Compression {
+    fn from_hts(format: &htslib::htsFormat) -> Self {
+        match format.compression {
+            htslib::gzip => Compression::Gzip,
+            htslib::bgzf => Compression::BGZF,
+            htslib::no_compression => Compression::None,
+            _            => Compression::Custom,
+        }
     }
 }
 
 
-pub struct HeaderView {
-    inner: *mut htslib::bam_hdr_t,
-    owned: bool,
+/// CRAM options taking an integer value, see `CramReader::set_option` and
+/// `CramWriter::set_option`. The reference is set with `set_reference`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CramOption {
+    /// Whether to generate MD and NM tags when decoding (1) or not (0).
+    DecodeMd,
+    /// Verbosity of the CRAM codec.
+    Verbosity,
+    /// Number of records per slice.
+    SeqsPerSlice,
+    /// Number of slices per container.
+    SlicesPerContainer,
+    /// Whether to embed the reference in the file (1) or not (0).
+    EmbedRef,
+    /// Whether to skip checking the MD5 sums of the reference (1) or not (0).
+    IgnoreMd5,
+    /// Whether slices may contain records of multiple references (1) or not (0).
+    MultiSeqPerSlice,
+    /// Whether to encode without reference (1) or not (0).
+    NoRef,
+    /// Whether to use bzip2 compression (1) or not (0).
+    UseBzip2,
+    /// Whether to use LZMA compression (1) or not (0).
+    UseLzma,
+    /// Whether to use rANS compression (1) or not (0).
+    UseRans,
+    /// Bit mask of the fields that are needed when decoding (`SAM_*` in sam.h).
+    RequiredFields,
 }
 
 
-impl HeaderView {
-    fn new(inner: *mut htslib::bam_hdr_t) -> Self {
-        HeaderView { 
-            inner: inner,
-            owned: true,
+impl CramOption {
+    fn hts_option(&self) -> htslib::Enum_cram_option {
+        match *self {
+            CramOption::DecodeMd           => htslib::CRAM_OPT_DECODE_MD,
+            CramOption::Verbosity          => htslib::CRAM_OPT_VERBOSITY,
+            CramOption::SeqsPerSlice       => htslib::CRAM_OPT_SEQS_PER_SLICE,
+            CramOption::SlicesPerContainer => htslib::CRAM_OPT_SLICES_PER_CONTAINER,
+            CramOption::EmbedRef           => htslib::CRAM_OPT_EMBED_REF,
+            CramOption::IgnoreMd5          => htslib::CRAM_OPT_IGNORE_MD5,
+            CramOption::MultiSeqPerSlice   => htslib::CRAM_OPT_MULTI_SEQ_PER_SLICE,
+            CramOption::NoRef              => htslib::CRAM_OPT_NO_REF,
+            CramOption::UseBzip2           => htslib::CRAM_OPT_USE_BZIP2,
+            CramOption::UseLzma            => htslib::CRAM_OPT_USE_LZMA,
+            CramOption::UseRans            => htslib::CRAM_OPT_USE_RANS,
+            CramOption::RequiredFields     => htslib::CRAM_OPT_REQUIRED_FIELDS,
         }
     }
+}
 
-    fn borrow(inner: *mut htslib::bam_hdr_t) -> Self {
-        HeaderView { 
-            inner: inner,
-            owned: false,
+
+/// A BAM writer.
+pub struct Writer {
+    f: *mut htslib::Struct_BGZF,
+    header: HeaderView,
+    path: ffi::CString,
+    idx: Option<(*mut htslib::hts_idx_t, ::libc::c_int)>,
+    last: Option<(u32, i32)>,
+    threaded: bool,
+    written: bool,
+}
+
+
+unsafe impl Send for Writer {}
+
+
+impl Writer {
+    /// Create a new BAM file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - the path.
+    /// * `header` - header definition to use
+    pub fn from_path<P: AsRef<Path>>(path: P, header: &header::Header) -> Result<Self, WriterPathError> {
+        if let Some(p) = path.as_ref().to_str() {
+                Ok(try!(Self::new(p.as_bytes(), header)))
+        } else {
+            Err(WriterPathError::InvalidPath)
         }
     }
 
-    #[inline]
-    fn inner(&self) -> htslib::bam_hdr_t {
-        unsafe { (*self.inner) }
+    /// Create a new BAM file at STDOUT.
+    ///
+    /// # Arguments
+    ///
+    /// * `header` - header definition to use
+    pub fn from_stdout(header: &header::Header) -> Result<Self, BGZFError> {
+        Self::new(b"-", header)
     }
 
-    pub fn tid(&self, name: &[u8]) -> Option<u32> {
-        let tid = unsafe {
-            htslib::bam_name2id(
-                self.inner,
-                ffi::CString::new(name).ok().expect("Expected valid name.").as_ptr()
-            )
-        };
-        if tid < 0 {
-            None
+    /// Create a new BAM file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - the path. Use "-" for stdin.
+    /// * `header` - header definition to use
+    fn new(path: &[u8], header: &header::Header) -> Result<Self, BGZFError> {
+        let path = ffi::CString::new(path).unwrap();
+        let f = try!(bgzf_open(&path, b"w"));
+        let header_record = header_record(header);
+
+        if unsafe { htslib::bam_hdr_write(f, header_record) } < 0 {
+            unsafe {
+                htslib::bam_hdr_destroy(header_record);
+                htslib::bgzf_close(f);
+            }
+            return Err(BGZFError::Some);
+        }
+
+        Ok(Writer {
+            f: f,
+            header: HeaderView::new(header_record),
+            path: path,
+            idx: None,
+            last: None,
+            threaded: false,
+            written: false
+        })
+    }
+
+    /// Activate multi-threaded BAM write support in htslib. This should permit faster
+    /// writing of large BAM files.
+    /// Multi-threading cannot be combined with building an index on the fly.
+    /// # Arguments
+    ///
+    /// * `n_threads` - number of background writer threads to use
+    pub fn set_threads(&mut self, n_threads: usize) -> Result<(), ThreadingError> {
+        if self.idx.is_some() {
+            return Err(ThreadingError::Some);
+        }
+        try!(bgzf_set_threads(self.f, n_threads));
+        self.threaded = true;
+        Ok(())
+    }
+
+    /// Build an index while writing. Records have to be written in coordinate order.
+    /// The index is saved next to the BAM file (with extension `.bai` or `.csi`) by `close`,
+    /// or when the writer is dropped.
+    /// This has to be called before writing the first record, and cannot be combined
+    /// with multi-threading or writing to STDOUT.
+    ///
+    /// # Arguments
+    ///
+    /// * `idx_type` - the type of the index
+    pub fn build_index(&mut self, idx_type: index::IndexType) -> Result<(), index::BuildError> {
+        if self.threaded || self.written || self.idx.is_some() || self.path.as_bytes() == b"-" {
+            return Err(index::BuildError::Unsupported);
+        }
+
+        let (fmt, min_shift, n_lvls) = match idx_type {
+            index::IndexType::Bai => (htslib::HTS_FMT_BAI, 14, 5),
+            index::IndexType::Csi { min_shift } => {
+                // choose the number of levels such that the longest contig fits (as in sam.c)
+                let max_len = (0..self.header.target_count())
+                    .map(|tid| self.header.target_len(tid).unwrap() as i64)
+                    .max().unwrap_or(0) + 256;
+                let (mut n_lvls, mut s) = (0, 1i64 << min_shift);
+                while max_len > s {
+                    n_lvls += 1;
+                    s <<= 3;
+                }
+                (htslib::HTS_FMT_CSI, min_shift as i32, n_lvls)
+            }
+        };
+
+        let idx = unsafe {
+            htslib::hts_idx_init(
+                self.header.target_count() as i32,
+                fmt,
+                htslib::bgzf_tell(self.f) as u64,
+                min_shift,
+                n_lvls
+            )
+        };
+        if idx.is_null() {
+            Err(index::BuildError::Some)
+        } else {
+            self.idx = Some((idx, fmt));
+            Ok(())
+        }
+    }
+
+    /// Write record to BAM.
+    ///
+    /// # Arguments
+    ///
+    /// * `record` - the record to write
+    pub fn write(&mut self, record: &record::Record) -> Result<(), WriteError> {
+        self.written = true;
+        if self.idx.is_some() {
+            // unmapped reads without coordinate (tid -1) go last
+            let pos = (record.tid() as u32, record.pos());
+            if self.last.map_or(false, |last| pos < last) {
+                return Err(WriteError::Unsorted);
+            }
+            self.last = Some(pos);
+        }
+        if unsafe { htslib::bam_write1(self.f, record.inner) } == -1 {
+            return Err(WriteError::Some);
+        }
+        if let Some((idx, _)) = self.idx {
+            let ret = unsafe {
+                htslib::hts_idx_push(
+                    idx,
+                    record.tid(),
+                    record.pos(),
+                    htslib::bam_endpos(record.inner),
+                    htslib::bgzf_tell(self.f) as u64,
+                    !record.is_unmapped() as i32
+                )
+            };
+            if ret < 0 {
+                return Err(WriteError::Index);
+            }
+        }
+        Ok(())
+    }
+
+    /// Close the BAM file, saving the index if it is built on the fly. In contrast to
+    /// dropping the writer, this reports errors when flushing the file or saving the index.
+    pub fn close(mut self) -> Result<(), WriteError> {
+        self.finish()
+    }
+
+    /// Save the index (if any) and close the underlying file, unless that already happened.
+    fn finish(&mut self) -> Result<(), WriteError> {
+        if self.f.is_null() {
+            return Ok(());
+        }
+        let idx = self.idx.take();
+        if let Some((idx, _)) = idx {
+            unsafe { htslib::hts_idx_finish(idx, htslib::bgzf_tell(self.f) as u64) };
+        }
+        let closed = unsafe { htslib::bgzf_close(self.f) } >= 0;
+        self.f = ptr::null_mut();
+
+        let saved = match idx {
+            Some((idx, fmt)) => {
+                let mut idx_path = self.path.to_str().unwrap().to_owned();
+                idx_path.push_str(if fmt == htslib::HTS_FMT_BAI { ".bai" } else { ".csi" });
+                // hts_idx_save does not report errors, hence check that the index file
+                // has been (re)created
+                fs::remove_file(&idx_path).ok();
+                unsafe {
+                    htslib::hts_idx_save(idx, self.path.as_ptr(), fmt);
+                    htslib::hts_idx_destroy(idx);
+                }
+                Path::new(&idx_path).exists()
+            },
+            None => true
+        };
+
+        if !closed {
+            Err(WriteError::Some)
+        } else if !saved {
+            Err(WriteError::Index)
+        } else {
+            Ok(())
+        }
+    }
+
+
+    /// Return the header.
+    pub fn header(&self) -> &HeaderView {
+        &self.header
+    }
+}
+
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        // best effort, use `close` to handle errors
+        self.finish().ok();
+    }
+}
+
+
+/// A SAM writer.
+pub struct SamWriter {
+    htsfile: *mut htslib::htsFile,
+    header: HeaderView,
+}
+
+
+unsafe impl Send for SamWriter {}
+
+
+impl SamWriter {
+    /// Create a new SAM file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - the path.
+    /// * `header` - header definition to use
+    pub fn from_path<P: AsRef<Path>>(path: P, header: &header::Header) -> Result<Self, WriterPathError> {
+        if let Some(p) = path.as_ref().to_str() {
+            Ok(try!(Self::new(p.as_bytes(), header)))
+        } else {
+            Err(WriterPathError::InvalidPath)
+        }
+    }
+
+    /// Create a new SAM file at STDOUT.
+    ///
+    /// # Arguments
+    ///
+    /// * `header` - header definition to use
+    pub fn from_stdout(header: &header::Header) -> Result<Self, HTSError> {
+        Self::new(b"-", header)
+    }
+
+    /// Create a new SAM file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - the path. Use "-" for stdout.
+    /// * `header` - header definition to use
+    fn new(path: &[u8], header: &header::Header) -> Result<Self, HTSError> {
+        let htsfile = try!(hts_open(&ffi::CString::new(path).unwrap(), b"w"));
+        let header_record = header_record(header);
+
+        if unsafe { htslib::sam_hdr_write(htsfile, header_record) } < 0 {
+            unsafe {
+                htslib::bam_hdr_destroy(header_record);
+                htslib::hts_close(htsfile);
+            }
+            return Err(HTSError::Some);
+        }
+
+        Ok(SamWriter { htsfile: htsfile, header: HeaderView::new(header_record) })
+    }
+
+    /// Write record to SAM.
+    ///
+    /// # Arguments
+    ///
+    /// * `record` - the record to write
+    pub fn write(&mut self, record: &record::Record) -> Result<(), WriteError> {
+        if unsafe { htslib::sam_write1(self.htsfile, self.header.inner, record.inner) } == -1 {
+            Err(WriteError::Some)
         }
         else {
-            Some(tid as u32)
+            Ok(())
+        }
+    }
+
+    /// Return the header.
+    pub fn header(&self) -> &HeaderView {
+        &self.header
+    }
+}
+
+
+impl Drop for SamWriter {
+    fn drop(&mut self) {
+        unsafe {
+            htslib::hts_close(self.htsfile);
+        }
+    }
+}
+
+
+/// A CRAM writer.
+///
+/// The header is written when the first record is written (or when the writer is dropped),
+/// so that the reference and other CRAM options can be configured after creating the writer.
+pub struct CramWriter {
+    htsfile: *mut htslib::htsFile,
+    header: HeaderView,
+    header_written: bool,
+}
+
+
+unsafe impl Send for CramWriter {}
+
+
+impl CramWriter {
+    /// Create a new CRAM file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - the path.
+    /// * `header` - header definition to use
+    pub fn from_path<P: AsRef<Path>>(path: P, header: &header::Header) -> Result<Self, WriterPathError> {
+        if let Some(p) = path.as_ref().to_str() {
+            Ok(try!(Self::new(p.as_bytes(), header)))
+        } else {
+            Err(WriterPathError::InvalidPath)
+        }
+    }
+
+    /// Create a new CRAM file at STDOUT.
+    ///
+    /// # Arguments
+    ///
+    /// * `header` - header definition to use
+    pub fn from_stdout(header: &header::Header) -> Result<Self, HTSError> {
+        Self::new(b"-", header)
+    }
+
+    /// Create a new CRAM file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - the path. Use "-" for stdout.
+    /// * `header` - header definition to use
+    fn new(path: &[u8], header: &header::Header) -> Result<Self, HTSError> {
+        let htsfile = try!(hts_open(&ffi::CString::new(path).unwrap(), b"wc"));
+        Ok(CramWriter { htsfile: htsfile, header: HeaderView::new(header_record(header)), header_written: false })
+    }
+
+    /// Set the reference FASTA used to encode the CRAM records.
+    /// The FASTA has to be indexed with `samtools faidx`.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - the path to the reference FASTA.
+    pub fn set_reference<P: AsRef<Path>>(&mut self, path: P) -> Result<(), CRAMError> {
+        cram_set_reference(self.htsfile, path)
+    }
+
+    /// Set the number of records per CRAM slice.
+    ///
+    /// # Arguments
+    ///
+    /// * `n` - number of records per slice
+    pub fn set_seqs_per_slice(&mut self, n: u32) -> Result<(), CRAMError> {
+        cram_set_option(self.htsfile, CramOption::SeqsPerSlice, n as i32)
+    }
+
+    /// Set the number of slices per CRAM container.
+    ///
+    /// # Arguments
+    ///
+    /// * `n` - number of slices per container
+    pub fn set_slices_per_container(&mut self, n: u32) -> Result<(), CRAMError> {
+        cram_set_option(self.htsfile, CramOption::SlicesPerContainer, n as i32)
+    }
+
+    /// Set a CRAM option.
+    ///
+    /// # Arguments
+    ///
+    /// * `option` - the option to set
+    /// * `value` - the value to set
+    pub fn set_option(&mut self, option: CramOption, value: i32) -> Result<(), CRAMError> {
+        cram_set_option(self.htsfile, option, value)
+    }
+
+    /// Activate multi-threaded CRAM write support in htslib.
+    ///
+    /// # Arguments
+    ///
+    /// * `n_threads` - number of background writer threads to use
+    pub fn set_threads(&mut self, n_threads: usize) -> Result<(), ThreadingError> {
+        hts_set_threads(self.htsfile, n_threads)
+    }
+
+    fn write_header(&mut self) -> Result<(), WriteError> {
+        if !self.header_written {
+            self.header_written = true;
+            if unsafe { htslib::sam_hdr_write(self.htsfile, self.header.inner) } < 0 {
+                return Err(WriteError::Some);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write record to CRAM.
+    ///
+    /// # Arguments
+    ///
+    /// * `record` - the record to write
+    pub fn write(&mut self, record: &record::Record) -> Result<(), WriteError> {
+        try!(self.write_header());
+        if unsafe { htslib::sam_write1(self.htsfile, self.header.inner, record.inner) } == -1 {
+            Err(WriteError::Some)
+        }
+        else {
+            Ok(())
+        }
+    }
+
+    /// Close the CRAM file, writing the header if no record has been written. In contrast to
+    /// dropping the writer, this reports errors when writing the header or flushing the file.
+    pub fn close(mut self) -> Result<(), WriteError> {
+        self.finish()
+    }
+
+    /// Write the header (if not yet done) and close the underlying file, unless that
+    /// already happened.
+    fn finish(&mut self) -> Result<(), WriteError> {
+        if self.htsfile.is_null() {
+            return Ok(());
+        }
+        let written = self.write_header();
+        let closed = unsafe { htslib::hts_close(self.htsfile) } >= 0;
+        self.htsfile = ptr::null_mut();
+        try!(written);
+        if closed {
+            Ok(())
+        } else {
+            Err(WriteError::Some)
+        }
+    }
+
+    /// Return the header.
+    pub fn header(&self) -> &HeaderView {
+        &self.header
+    }
+}
+
+
+impl Drop for CramWriter {
+    fn drop(&mut self) {
+        // best effort, use `close` to handle errors
+        self.finish().ok();
+    }
+}
+
+
+/// Iterator over the records of a BAM.
+pub struct Records<'a, R: 'a + Read> {
+    reader: &'a R
+}
+
+
+impl<'a, R: Read> Iterator for Records<'a, R> {
+    type Item = Result<record::Record, ReadError>;
+
+    fn next(&mut self) -> Option<Result<record::Record, ReadError>> {
+        let mut record = record::Record::new();
+        match self.reader.read(&mut record) {
+            Err(ReadError::NoMoreRecord) => None,
+            Ok(())   => Some(Ok(record)),
+            Err(err) => Some(Err(err))
+        }
+    }
+}
+
+
+/// Mapped and unmapped read counts of a contig, as stored in a BAM index.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexStats {
+    pub tid: u32,
+    pub name: Vec<u8>,
+    pub len: u32,
+    pub mapped: u64,
+    pub unmapped: u64,
+}
+
+
+/// Maximum distance between mate positions that are fetched with a single index query.
+const MATE_BATCH_GAP: i32 = 16384;
+
+
+/// Iterator over the records of multiple regions of an indexed BAM,
+/// see `IndexedReader::fetch_regions`.
+pub struct RegionRecords<'a> {
+    reader: &'a mut IndexedReader,
+    regions: Vec<(u32, u32, u32)>,
+    itrs: Vec<*mut htslib::hts_itr_t>,
+    i: usize,
+    prev: Option<(u32, u32, u32)>,
+}
+
+
+impl<'a> Iterator for RegionRecords<'a> {
+    type Item = Result<record::Record, ReadError>;
+
+    fn next(&mut self) -> Option<Result<record::Record, ReadError>> {
+        let mut record = record::Record::new();
+        while self.i < self.itrs.len() {
+            match itr_next(self.reader.htsfile, self.itrs[self.i], record.inner) {
+                -1 => {
+                    self.prev = Some(self.regions[self.i]);
+                    self.i += 1;
+                },
+                -2 => return Some(Err(ReadError::Truncated)),
+                r if r < 0 => return Some(Err(ReadError::Invalid)),
+                _  => {
+                    // Regions are disjoint and sorted. Hence, a record has already been
+                    // returned iff it starts before the end of the previous region.
+                    match self.prev {
+                        Some((tid, _, end)) if tid as i32 == record.tid() && record.pos() < end as i32 => (),
+                        _ => return Some(Ok(record))
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+
+impl<'a> Drop for RegionRecords<'a> {
+    fn drop(&mut self) {
+        for &itr in &self.itrs {
+            unsafe { htslib::hts_itr_destroy(itr) };
+        }
+    }
+}
+
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum ReadError {
+        Truncated {
+            description("truncated record")
+        }
+        Invalid {
+            description("invalid record")
+        }
+        NoMoreRecord {
+            description("no more record")
+        }
+    }
+}
+
+
+impl ReadError {
+    /// Returns true if no record has been read because the end of the file was reached.
+    pub fn is_eof(&self) -> bool {
+        match self {
+            &ReadError::NoMoreRecord => true,
+            _ => false
+        }
+    }
+}
+
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum IndexedReaderError {
+        InvalidIndex {
+            description("invalid index")
+        }
+        Unsupported {
+            description("operation not supported for this file format")
+        }
+        BGZFError(err: BGZFError) {
+            from()
+        }
+        HTSError(err: HTSError) {
+            from()
+        }
+    }
+}
+
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum WriterPathError {
+        InvalidPath {
+            description("invalid path")
+        }
+        BGZFError(err: BGZFError) {
+            from()
+        }
+        HTSError(err: HTSError) {
+            from()
+        }
+    }
+}
+
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum IndexedReaderPathError {
+        InvalidPath {
+            description("invalid path")
+        }
+        IndexedReaderError(err: IndexedReaderError) {
+            from()
+        }
+    }
+}
+
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum BGZFError {
+        Some {
+            description("error reading BGZF file")
+        }
+    }
+}
+
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum HTSError {
+        Some {
+            description("error opening SAM/BAM/CRAM file")
+        }
+        UnsupportedFormat {
+            description("file is not in SAM, BAM or CRAM format")
+        }
+        InvalidHeader {
+            description("invalid or missing header")
+        }
+    }
+}
+
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum ReaderPathError {
+        InvalidPath {
+            description("invalid path")
+        }
+        BGZFError(err: BGZFError) {
+            from()
+        }
+        HTSError(err: HTSError) {
+            from()
+        }
+    }
+}
+
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum ThreadingError {
+        Some {
+            description("error setting threads for multi-threaded reading or writing")
+        }
+    }
+}
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum CRAMError {
+        InvalidReference {
+            description("error loading reference")
+        }
+        InvalidOption {
+            description("error setting CRAM option")
+        }
+    }
+}
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum WriteError {
+        Some {
+            description("error writing record")
+        }
+        Unsorted {
+            description("records are not sorted by coordinate, cannot build index")
+        }
+        Index {
+            description("error building or saving index")
+        }
+    }
+}
+
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum SeekError {
+        Some {
+            description("error seeking to locus")
+        }
+    }
+}
+
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum FetchMateError {
+        SeekError(err: SeekError) {
+            from()
+        }
+        ReadError(err: ReadError) {
+            from()
+        }
+    }
+}
+
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum FetchError {
+        InvalidRegion(region: String) {
+            description("invalid region")
+            display("invalid region: {}", region)
+        }
+        UnknownContig(contig: String) {
+            description("unknown contig")
+            display("unknown contig: {}", contig)
+        }
+        Some {
+            description("error fetching region")
+        }
+    }
+}
+
+
+/// Wrapper for activating multi-threaded compression or decompression of a SAM/BAM/CRAM file.
+fn hts_set_threads(htsfile: *mut htslib::htsFile, n_threads: usize) -> Result<(), ThreadingError> {
+    if unsafe { htslib::hts_set_threads(htsfile, n_threads as ::libc::c_int) } != 0 {
+        Err(ThreadingError::Some)
+    } else {
+        Ok(())
+    }
+}
+
+
+/// Wrapper for opening a BAM file.
+fn bgzf_open(path: &ffi::CStr, mode: &[u8]) -> Result<*mut htslib::Struct_BGZF, BGZFError> {
+    let ret = unsafe {
+        htslib::bgzf_open(
+            path.as_ptr(),
+            ffi::CString::new(mode).unwrap().as_ptr()
+        )
+    };
+    if ret.is_null() {
+        Err(BGZFError::Some)
+    } else {
+        Ok(ret)
+    }
+}
+
+
+/// Wrapper for activating multi-threaded BGZF compression.
+fn bgzf_set_threads(bgzf: *mut htslib::Struct_BGZF, n_threads: usize) -> Result<(), ThreadingError> {
+    if unsafe { htslib::bgzf_mt(bgzf, n_threads as ::libc::c_int, 256) } != 0 {
+        Err(ThreadingError::Some)
+    } else {
+        Ok(())
+    }
+}
+
+
+/// Wrapper for opening a SAM/BAM/CRAM file with htslib's generic file API.
+fn hts_open(path: &ffi::CStr, mode: &[u8]) -> Result<*mut htslib::htsFile, HTSError> {
+    let ret = unsafe {
+        htslib::hts_open(
+            path.as_ptr(),
+            ffi::CString::new(mode).unwrap().as_ptr()
+        )
+    };
+    if ret.is_null() {
+        Err(HTSError::Some)
+    } else {
+        Ok(ret)
+    }
+}
+
+
+/// Return the detected format of the given htsFile.
+fn hts_format<'a>(htsfile: *mut htslib::htsFile) -> &'a htslib::htsFormat {
+    unsafe { &*htslib::hts_get_format(htsfile) }
+}
+
+
+/// Return the BGZF handle underlying the given htsFile opened for reading,
+/// or a null pointer if the file is not backed by BGZF (i.e. CRAM).
+fn htsfile_bgzf(htsfile: *mut htslib::htsFile) -> *mut htslib::Struct_BGZF {
+    unsafe {
+        if hts_format(htsfile).format == htslib::cram {
+            ptr::null_mut()
+        } else {
+            *(*htsfile).fp.bgzf()
+        }
+    }
+}
+
+
+/// Set the reference FASTA of a CRAM file.
+fn cram_set_reference<P: AsRef<Path>>(htsfile: *mut htslib::htsFile, path: P) -> Result<(), CRAMError> {
+    match utils::path_to_cstring(&path) {
+        Some(p) => {
+            if unsafe { htslib::hts_set_opt(htsfile, htslib::CRAM_OPT_REFERENCE, p.as_ptr()) } != 0 {
+                Err(CRAMError::InvalidReference)
+            } else {
+                Ok(())
+            }
+        },
+        None => Err(CRAMError::InvalidReference)
+    }
+}
+
+
+/// Set a CRAM option taking an integer value.
+fn cram_set_option(htsfile: *mut htslib::htsFile, option: CramOption, value: i32) -> Result<(), CRAMError> {
+    if unsafe { htslib::hts_set_opt(htsfile, option.hts_option(), value as ::libc::c_int) } != 0 {
+        Err(CRAMError::InvalidOption)
+    } else {
+        Ok(())
+    }
+}
+
+
+/// Build a header record from the given header definition.
+fn header_record(header: &header::Header) -> *mut htslib::bam_hdr_t {
+    // sam_hdr_parse does not populate the text and l_text fields of the header_record.
+    // This causes non-SQ headers to be dropped in the output BAM file.
+    // To avoid this, we copy the full header to a new C-string that is allocated with malloc,
+    // and set this into header_record manually.
+    unsafe {
+        let header_string = header.to_bytes();
+
+        let l_text = header_string.len();
+        let text = ::libc::malloc(l_text + 1);
+        ::libc::memset(text, 0, l_text + 1);
+        ::libc::memcpy(text, header_string.as_ptr() as *const ::libc::c_void, header_string.len());
+
+        let rec = htslib::sam_hdr_parse(
+            (l_text + 1) as i32,
+            text as *const i8,
+        );
+
+        (*rec).text = text as *mut i8;
+        (*rec).l_text = l_text as u32;
+        rec
+    }
+}
+
+
+/// Load the index at the given path. CRAM indices are loaded via `sam_index_load`,
+/// which expects the path of the data file. BAI and CSI indices are loaded via `hts_idx_load`,
+/// which also accepts the index path itself, as long as it ends with `.bai` or `.csi`
+/// (a `.csi` file with the same stem takes precedence over a given `.bai` file).
+fn index_load(htsfile: *mut htslib::htsFile, index_path: &ffi::CStr) -> *mut htslib::hts_idx_t {
+    let index = index_path.to_bytes();
+    if index.ends_with(b".crai") {
+        let data_path = ffi::CString::new(&index[..index.len() - 5]).unwrap();
+        unsafe { htslib::sam_index_load(htsfile, data_path.as_ptr()) }
+    } else if index.ends_with(b".bai") || index.ends_with(b".csi") {
+        // the format is determined from the extension
+        unsafe { htslib::hts_idx_load(index_path.as_ptr(), htslib::HTS_FMT_BAI) }
+    } else {
+        ptr::null_mut()
+    }
+}
+
+
+/// Whether the candidate is the primary record of the mate of the given record.
+fn is_mate(record: &record::Record, candidate: &record::Record) -> bool {
+    !candidate.is_secondary() && !candidate.is_supplementary() &&
+    candidate.qname() == record.qname() &&
+    candidate.tid() == record.mtid() && candidate.pos() == record.mpos() &&
+    candidate.is_first_in_template() == record.is_last_in_template() &&
+    candidate.is_last_in_template() == record.is_first_in_template()
+}
+
+/// Read the next record of a SAM/BAM/CRAM file.
+fn sam_read(htsfile: *mut htslib::htsFile, header: *mut htslib::bam_hdr_t, record: *mut htslib::bam1_t) -> Result<(), ReadError> {
+    match unsafe { htslib::sam_read1(htsfile, header, record) } {
+        -1         => Err(ReadError::NoMoreRecord),
+        -2         => Err(ReadError::Truncated),
+        r if r < 0 => Err(ReadError::Invalid),
+        _          => Ok(())
+    }
+}
+
+
+/// Read the next record of an index iterator.
+fn itr_read(htsfile: *mut htslib::htsFile, itr: *mut htslib::hts_itr_t, record: *mut htslib::bam1_t) -> Result<(), ReadError> {
+    match itr_next(htsfile, itr, record) {
+        -1 => Err(ReadError::NoMoreRecord),
+        -2 => Err(ReadError::Truncated),
+        -4 => Err(ReadError::Invalid),
+        _  => Ok(())
+    }
+}
+
+
+/// Wrapper for iterating an indexed SAM/BAM/CRAM file (analogous to `sam_itr_next`).
+fn itr_next(htsfile: *mut htslib::htsFile, itr: *mut htslib:: hts_itr_t, record: *mut htslib::bam1_t) -> i32 {
+    unsafe {
+        htslib::hts_itr_next(
+            *(*htsfile).fp.bgzf(),
+            itr,
+            record as *mut ::libc::c_void,
+            htsfile as *mut ::libc::c_void
+        )
+    }
+}
+
+
+pub struct HeaderView {
+    inner: *mut htslib::bam_hdr_t,
+    owned: bool,
+}
+
+
+impl HeaderView {
+    fn new(inner: *mut htslib::bam_hdr_t) -> Self {
+        HeaderView { 
+            inner: inner,
+            owned: true,
+        }
+    }
+
+    fn borrow(inner: *mut htslib::bam_hdr_t) -> Self {
+        HeaderView { 
+            inner: inner,
+            owned: false,
+        }
+    }
+
+    #[inline]
+    fn inner(&self) -> htslib::bam_hdr_t {
+        unsafe { (*self.inner) }
+    }
+
+    pub fn tid(&self, name: &[u8]) -> Option<u32> {
+        let tid = unsafe {
+            htslib::bam_name2id(
+                self.inner,
+                ffi::CString::new(name).ok().expect("Expected valid name.").as_ptr()
+            )
+        };
+        if tid < 0 {
+            None
+        }
+        else {
+            Some(tid as u32)
+        }
+    }
+
+    pub fn target_count(&self) -> u32 {
+        self.inner().n_targets as u32
+    }
+
+    pub fn target_names(&self) -> Vec<&[u8]> {
+        let names = unsafe { slice::from_raw_parts(self.inner().target_name, self.target_count() as usize) };
+        names.iter().map(|name| unsafe { ffi::CStr::from_ptr(*name).to_bytes() }).collect()
+    }
+
+    pub fn target_len(&self, tid: u32) -> Option<u32> {
+        let inner = unsafe { *self.inner };
+        if (tid as i32) < inner.n_targets {
+            let l: &[u32] = unsafe { slice::from_raw_parts(inner.target_len, inner.n_targets as usize) };
+            Some(l[tid as usize])
+        }
+        else {
+            None
+        }
+    }
+
+    /// Retrieve the textual SAM header as bytes
+    pub fn as_bytes<'a>(&'a self) -> &'a [u8] {
+        unsafe{ ffi::CStr::from_ptr((*self.inner).text).to_bytes() }
+    }
+}
+
+impl Drop for HeaderView {
+    fn drop(&mut self) {
+        if self.owned {
+            unsafe { htslib::bam_hdr_destroy(self.inner); }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+    use super::*;
+    use super::record::{Cigar,Aux};
+    use super::header::HeaderRecord;
+    use std::str;
+    use std::fs;
+    use std::path::Path;
+    use std::cmp;
+
+    fn gold() -> ([&'static [u8]; 6], [u16; 6], [&'static [u8]; 6], [&'static [u8]; 6], [[Cigar; 3]; 6]) {
+        let names = [&b"I"[..], &b"II.14978392"[..], &b"III"[..], &b"IV"[..], &b"V"[..], &b"VI"[..]];
+        let flags = [16u16, 16u16, 16u16, 16u16, 16u16, 2048u16];
+        let seqs = [
+            &b"CCTAGCCCTAACCCTAACCCTAACCCTAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAA"[..],
+            &b"CCTAGCCCTAACCCTAACCCTAACCCTAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAA"[..],
+            &b"CCTAGCCCTAACCCTAACCCTAACCCTAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAA"[..],
+            &b"CCTAGCCCTAACCCTAACCCTAACCCTAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAA"[..],
+            &b"CCTAGCCCTAACCCTAACCCTAACCCTAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAA"[..],
+            &b"ACTAAGCCTAAGCCTAAGCCTAAGCCAATTATCGATTTCTGAAAAAATTATCGAATTTTCTAGAAATTTTGCAAATTTTTTCATAAAATTATCGATTTTA"[..],
+        ];
+        let quals = [
+            &b"#############################@B?8B?BA@@DDBCDDCBC@CDCDCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCC"[..],
+            &b"#############################@B?8B?BA@@DDBCDDCBC@CDCDCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCC"[..],
+            &b"#############################@B?8B?BA@@DDBCDDCBC@CDCDCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCC"[..],
+            &b"#############################@B?8B?BA@@DDBCDDCBC@CDCDCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCC"[..],
+            &b"#############################@B?8B?BA@@DDBCDDCBC@CDCDCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCC"[..],
+            &b"#############################@B?8B?BA@@DDBCDDCBC@CDCDCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCC"[..],
+        ];
+        let cigars = [
+            [Cigar::Match(27), Cigar::Del(1), Cigar::Match(73)],
+            [Cigar::Match(27), Cigar::Del(1), Cigar::Match(73)],
+            [Cigar::Match(27), Cigar::Del(1), Cigar::Match(73)],
+            [Cigar::Match(27), Cigar::Del(1), Cigar::Match(73)],
+            [Cigar::Match(27), Cigar::Del(1), Cigar::Match(73)],
+            [Cigar::Match(27), Cigar::Del(100000), Cigar::Match(73)],
+        ];
+        (names, flags, seqs, quals, cigars)
+    }
+
+    /// Collect a value from each pileup.
+    fn collect_pileups<T, F: FnMut(&pileup::Pileup) -> T>(mut pileups: pileup::Pileups, mut f: F) -> Vec<T> {
+        let mut values = Vec::new();
+        while let Some(p) = pileups.next() {
+            values.push(f(&p.ok().expect("Expected successful pileup.")));
+        }
+        values
+    }
+
+    #[test]
+    fn test_read() {
+        let (names, flags, seqs, quals, cigars) = gold();
+        let bam = Reader::from_path(&Path::new("test/test.bam")).ok().expect("Error opening file.");
+
+        for (i, record) in bam.records().enumerate() {
+            let rec = record.ok().expect("Expected valid record");
+            println!("{}", str::from_utf8(rec.qname()).ok().unwrap());
+            assert_eq!(rec.qname(), names[i]);
+            assert_eq!(rec.flags(), flags[i]);
+            assert_eq!(rec.seq().as_bytes(), seqs[i]);
+            assert_eq!(**rec.cigar(), cigars[i]);
+            assert_eq!(rec.end_pos(), rec.pos() + 100 + if i == 5 { 100000 } else { 1 });
+            // fix qual offset
+            let qual: Vec<u8> = quals[i].iter().map(|&q| q - 33).collect();
+            assert_eq!(rec.qual(), &qual[..]);
+        }
+    }
+
+    #[test]
+    fn test_read_sam() {
+        let (names, flags, seqs, quals, cigars) = gold();
+        let sam = SamReader::from_path(&"test/test.sam").ok().expect("Error opening file.");
+
+        let mut n = 0;
+        for (i, record) in sam.records().enumerate() {
+            let rec = record.ok().expect("Expected valid record");
+            assert_eq!(rec.qname(), names[i]);
+            assert_eq!(rec.flags(), flags[i]);
+            assert_eq!(rec.seq().as_bytes(), seqs[i]);
+            assert_eq!(**rec.cigar(), cigars[i]);
+            let qual: Vec<u8> = quals[i].iter().map(|&q| q - 33).collect();
+            assert_eq!(rec.qual(), &qual[..]);
+            n += 1;
+        }
+        assert_eq!(n, names.len());
+    }
+
+    #[test]
+    fn test_write_sam() {
+        let (names, _, seqs, quals, cigars) = gold();
+
+        let tmp = tempdir::TempDir::new("rust-htslib").ok().expect("Cannot create temp dir");
+        let sampath = tmp.path().join("test.sam");
+        {
+            let mut sam = SamWriter::from_path(
+                &sampath,
+                Header::new().push_record(
+                    HeaderRecord::new(b"SQ").push_tag(b"SN", &"chr1")
+                                            .push_tag(b"LN", &15072423)
+                )
+            ).ok().expect("Error opening file.");
+
+            for i in 0..names.len() {
+                let mut rec = record::Record::new();
+                rec.set(names[i], &cigars[i], seqs[i], quals[i]);
+                rec.push_aux(b"NM", &Aux::Integer(15));
+
+                sam.write(&rec).ok().expect("Failed to write record.");
+            }
+        }
+
+        {
+            let sam = SamReader::from_path(&sampath).ok().expect("Error opening file.");
+            assert_eq!(sam.header().target_names(), vec![&b"chr1"[..]]);
+
+            for i in 0..names.len() {
+                let mut rec = record::Record::new();
+                sam.read(&mut rec).ok().expect("Failed to read record.");
+
+                assert_eq!(rec.qname(), names[i]);
+                assert_eq!(**rec.cigar(), cigars[i]);
+                assert_eq!(rec.seq().as_bytes(), seqs[i]);
+                assert_eq!(rec.qual(), quals[i]);
+                assert_eq!(rec.aux(b"NM").unwrap(), Aux::Integer(15));
+            }
+        }
+
+        tmp.close().ok().expect("Failed to delete temp dir");
+    }
+
+    #[test]
+    fn test_write_cram() {
+        let reference = b"GCTAAAGACAATTACATAACATACACGTCAGCACGAAACTTGTTGGCCCAGTGTGAATCGCTTAAGGGTTAAGTAAGTGTGATGCATACGCCTTTACTTGCTGTGTCCACCCCATCGGACTGGCATTTTTATTACACTCAGAAACAGAACTCGGGTAATTTTGACAGGTCACGCAGAGGCGCGCCCTCCTGAAGTGCGTG";
+        let names = [&b"r1"[..], &b"r2"[..], &b"r3"[..]];
+        let positions = [0usize, 10, 120];
+        let qual = [b'I'; 50];
+
+        let tmp = tempdir::TempDir::new("rust-htslib").ok().expect("Cannot create temp dir");
+        let crampath = tmp.path().join("test.cram");
+        {
+            let mut cram = CramWriter::from_path(
+                &crampath,
+                Header::new().push_record(
+                    HeaderRecord::new(b"SQ").push_tag(b"SN", &"chr1")
+                                            .push_tag(b"LN", &200)
+                )
+            ).ok().expect("Error opening file.");
+            cram.set_reference(&"test/test_cram.fa").ok().expect("Error setting reference.");
+            cram.set_seqs_per_slice(2).ok().expect("Error setting CRAM option.");
+
+            for i in 0..names.len() {
+                let mut rec = record::Record::new();
+                rec.set(names[i], &[Cigar::Match(50)], &reference[positions[i]..positions[i] + 50], &qual);
+                rec.set_tid(0);
+                rec.set_pos(positions[i] as i32);
+                rec.set_mtid(-1);
+                rec.set_mpos(-1);
+
+                cram.write(&rec).ok().expect("Failed to write record.");
+            }
+            cram.close().ok().expect("Failed to close file.");
+        }
+
+        {
+            let mut cram = CramReader::from_path(&crampath).ok().expect("Error opening file.");
+            cram.set_reference(&"test/test_cram.fa").ok().expect("Error setting reference.");
+            cram.set_option(CramOption::DecodeMd, 0).ok().expect("Error setting CRAM option.");
+            cram.set_threads(2).ok().expect("Error setting threads.");
+            assert_eq!(cram.header().target_names(), vec![&b"chr1"[..]]);
+
+            let mut n = 0;
+            for (i, record) in cram.records().enumerate() {
+                let rec = record.ok().expect("Failed to read record.");
+                assert_eq!(rec.qname(), names[i]);
+                assert_eq!(rec.pos(), positions[i] as i32);
+                assert_eq!(**rec.cigar(), [Cigar::Match(50)]);
+                assert_eq!(rec.seq().as_bytes(), &reference[positions[i]..positions[i] + 50]);
+                assert_eq!(rec.qual(), &qual[..]);
+                n += 1;
+            }
+            assert_eq!(n, names.len());
+        }
+
+        {
+            // the header is written on close, even without records
+            let emptypath = tmp.path().join("empty.cram");
+            let mut cram = CramWriter::from_path(
+                &emptypath,
+                Header::new().push_record(
+                    HeaderRecord::new(b"SQ").push_tag(b"SN", &"chr1")
+                                            .push_tag(b"LN", &200)
+                )
+            ).ok().expect("Error opening file.");
+            cram.set_reference(&"test/test_cram.fa").ok().expect("Error setting reference.");
+            cram.close().ok().expect("Failed to close file.");
+
+            let cram = CramReader::from_path(&emptypath).ok().expect("Error opening file.");
+            assert_eq!(cram.header().target_names(), vec![&b"chr1"[..]]);
+            assert_eq!(cram.records().count(), 0);
+        }
+
+        {
+            index::build(&crampath, index::IndexType::Bai).ok().expect("Failed to build CRAM index.");
+            let bam = IndexedReader::from_path(&crampath).ok().expect("Expected valid index.");
+            match bam.idxstats() {
+                Err(IndexedReaderError::Unsupported) => (),
+                _ => panic!("Expected unsupported index statistics for CRAM.")
+            }
+        }
+
+        tmp.close().ok().expect("Failed to delete temp dir");
+    }
+
+    #[test]
+    fn test_read_any() {
+        let (names, _, seqs, _, cigars) = gold();
+
+        for &(path, format, compression) in [
+            ("test/test.bam", Format::BAM, Compression::BGZF),
+            ("test/test.sam", Format::SAM, Compression::None)
+        ].iter() {
+            let reader = AnyReader::from_path(&path).ok().expect("Error opening file.");
+            assert_eq!(reader.format(), format);
+            assert_eq!(reader.compression(), compression);
+            assert!(!reader.format_description().is_empty());
+            assert_eq!(reader.header().target_count(), 5);
+
+            let mut n = 0;
+            for (i, record) in reader.records().enumerate() {
+                let rec = record.ok().expect("Expected valid record");
+                assert_eq!(rec.qname(), names[i]);
+                assert_eq!(rec.seq().as_bytes(), seqs[i]);
+                assert_eq!(**rec.cigar(), cigars[i]);
+                n += 1;
+            }
+            assert_eq!(n, names.len());
+        }
+    }
+
+    #[test]
+    fn test_read_any_unsupported() {
+        match AnyReader::from_path(&"test/test.bcf") {
+            Err(ReaderPathError::HTSError(HTSError::UnsupportedFormat)) => (),
+            _ => panic!("Expected unsupported format error.")
+        }
+        match SamReader::from_path(&"test/test.bcf") {
+            Err(ReaderPathError::HTSError(HTSError::UnsupportedFormat)) => (),
+            _ => panic!("Expected unsupported format error.")
+        }
+        match CramReader::from_path(&"test/test.bcf") {
+            Err(ReaderPathError::HTSError(HTSError::UnsupportedFormat)) => (),
+            _ => panic!("Expected unsupported format error.")
+        }
+    }
+
+    #[test]
+    fn test_read_sam_header() {
+        let bam = Reader::from_path(&"test/test.bam").ok().expect("Error opening file.");
+
+        let true_header = "@SQ\tSN:CHROMOSOME_I\tLN:15072423\n@SQ\tSN:CHROMOSOME_II\tLN:15279345\n@SQ\tSN:CHROMOSOME_III\tLN:13783700\n@SQ\tSN:CHROMOSOME_IV\tLN:17493793\n@SQ\tSN:CHROMOSOME_V\tLN:20924149\n".to_string();
+        let header_text = String::from_utf8(bam.header().as_bytes().to_owned()).unwrap();
+        assert_eq!(header_text, true_header);
+    }
+
+
+    #[test]
+    fn test_cigar_string() {
+        let cigar: record::CigarString = "5H10S80M2I3D5N5M3S4H".parse().ok().expect("Expected valid CIGAR string.");
+        assert_eq!(cigar.to_string(), "5H10S80M2I3D5N5M3S4H");
+        assert_eq!(cigar.len(), 9);
+        assert_eq!(cigar[2], Cigar::Match(80));
+        assert_eq!(cigar.query_len(), 100);
+        assert_eq!(cigar.ref_len(), 93);
+        assert_eq!((cigar.leading_hardclips(), cigar.leading_softclips()), (5, 10));
+        assert_eq!((cigar.trailing_hardclips(), cigar.trailing_softclips()), (4, 3));
+
+        let empty: record::CigarString = "*".parse().ok().expect("Expected valid CIGAR string.");
+        assert!(empty.is_empty());
+        assert_eq!(empty.to_string(), "*");
+        for invalid in &["10M5", "M", "10Q", "300000000M"] {
+            assert!(invalid.parse::<record::CigarString>().is_err());
+        }
+        match "".parse::<record::CigarString>() {
+            Err(record::CigarError::MissingOperation) => (),
+            _ => panic!("Expected empty CIGAR string to be rejected.")
+        }
+
+        let bam = Reader::from_path(&"test/test.bam").ok().expect("Error opening file.");
+        let mut rec = record::Record::new();
+        while bam.read(&mut rec).is_ok() {
+            let cigar = rec.cigar().into_owned();
+            let raw = unsafe { rec.inner().data.offset(rec.inner().core.l_qname as isize) as *const u32 };
+            assert_eq!(cigar.query_len() as i32, unsafe { htslib::bam_cigar2qlen(cigar.len() as i32, raw) });
+            assert_eq!(cigar.ref_len() as i32, unsafe { htslib::bam_cigar2rlen(cigar.len() as i32, raw) });
+            assert_eq!(rec.end_pos(), unsafe { htslib::bam_endpos(rec.inner) });
+            assert_eq!(cigar.to_string().parse::<record::CigarString>().ok(), Some(cigar.clone()));
+
+            rec.cache_cigar();
+            assert_eq!(rec.cigar_cached(), Some(&cigar));
+            assert_eq!(rec.end_pos(), unsafe { htslib::bam_endpos(rec.inner) });
+        }
+        assert!(rec.cigar_cached().is_none());
+
+        let mut rec = record::Record::new();
+        rec.set(b"read", &[Cigar::Match(10)], b"ACGTACGTAC", b"IIIIIIIIII");
+        rec.set_pos(100);
+        rec.cache_cigar();
+        assert_eq!(rec.end_pos(), 110);
+        rec.set(b"read", &[Cigar::Match(5), Cigar::Del(3), Cigar::Match(5)], b"ACGTACGTAC", b"IIIIIIIIII");
+        assert!(rec.cigar_cached().is_none());
+        assert_eq!(**rec.cigar(), [Cigar::Match(5), Cigar::Del(3), Cigar::Match(5)]);
+        assert_eq!(rec.end_pos(), 113);
+
+        rec.cache_cigar();
+        rec.set_mapq(60);
+        rec.push_aux(b"NM", &Aux::Integer(0));
+        assert!(rec.cigar_cached().is_some());
+        rec.inner_mut().core.n_cigar = 1;
+        assert!(rec.cigar_cached().is_none());
+        assert_eq!(rec.end_pos(), 105);
+    }
+
+    #[test]
+    fn test_aligned_pairs() {
+        let cigar: record::CigarString = "2S3M1I2M2D2M3N2M".parse().ok().expect("Expected valid CIGAR string.");
+        let mut rec = record::Record::new();
+        rec.set(b"read", &cigar, b"ACGTACGTACGT", b"IIIIIIIIIIII");
+        rec.set_pos(100);
+
+        let pairs: Vec<_> = rec.aligned_pairs().collect();
+        let matched = [(2, 100), (3, 101), (4, 102), (6, 103), (7, 104), (8, 107), (9, 108), (10, 112), (11, 113)];
+        assert_eq!(pairs, matched.iter().map(|&(q, r)| (Some(q), Some(r))).collect::<Vec<_>>());
+
+        let pairs: Vec<_> = rec.aligned_pairs().insertions(true).soft_clips(true).deletions(true).introns(true).collect();
+        assert_eq!(pairs.len(), 12 + 2 + 3);
+        assert_eq!(&pairs[..3], &[(Some(0), None), (Some(1), None), (Some(2), Some(100))]);
+        assert_eq!(pairs[5], (Some(5), None));
+        assert_eq!(&pairs[8..10], &[(None, Some(105)), (None, Some(106))]);
+        assert_eq!(&pairs[12..15], &[(None, Some(109)), (None, Some(110)), (None, Some(111))]);
+        assert_eq!(pairs.last(), Some(&(Some(11), Some(113))));
+
+        assert_eq!(rec.aligned_blocks(), [(100, 103), (103, 105), (107, 109), (112, 114)]);
+        assert_eq!(rec.ref_pos_at(0), None);
+        assert_eq!(rec.ref_pos_at(2), Some(100));
+        assert_eq!(rec.ref_pos_at(5), None);
+        assert_eq!(rec.ref_pos_at(8), Some(107));
+        assert_eq!(rec.ref_pos_at(11), Some(113));
+        assert_eq!(rec.ref_pos_at(12), None);
+        assert_eq!(rec.query_pos_at(99), None);
+        assert_eq!(rec.query_pos_at(103), Some(6));
+        assert_eq!(rec.query_pos_at(105), None);
+        assert_eq!(rec.query_pos_at(110), None);
+        assert_eq!(rec.query_pos_at(112), Some(10));
+        assert_eq!(rec.query_pos_at(114), None);
+        for &(q, r) in &matched {
+            assert_eq!(rec.ref_pos_at(q), Some(r));
+            assert_eq!(rec.query_pos_at(r), Some(q));
+        }
+
+        rec.set_unmapped();
+        assert_eq!(rec.aligned_pairs().count(), 0);
+        assert!(rec.aligned_blocks().is_empty());
+        assert_eq!(rec.ref_pos_at(2), None);
+
+        // 27M1D73M at position 1
+        let bam = Reader::from_path(&"test/test.bam").ok().expect("Error opening file.");
+        let rec = bam.records().next().unwrap().ok().expect("Expected valid record.");
+        assert_eq!(rec.aligned_blocks(), [(1, 28), (29, 102)]);
+        assert_eq!(rec.ref_pos_at(27), Some(29));
+        assert_eq!(rec.query_pos_at(28), None);
+        assert_eq!(rec.aligned_pairs().count(), 100);
+    }
+
+    #[test]
+    fn test_read_indexed() {
+        let (names, flags, seqs, quals, cigars) = gold();
+        let mut bam = IndexedReader::from_path(&"test/test.bam").ok().expect("Expected valid index.");
+
+        let tid = bam.header.tid(b"CHROMOSOME_I").expect("Expected tid.");
+        assert!(bam.header.target_len(tid).expect("Expected target len.") == 15072423);
+
+        // seek to position containing reads
+        bam.seek(tid, 0, 2).ok().expect("Expected successful seek.");
+        assert!(bam.records().count() == 6);
+
+        // compare reads
+        bam.seek(tid, 0, 2).ok().expect("Expected successful seek.");
+        for (i, record) in bam.records().enumerate() {
+            let rec = record.ok().expect("Expected valid record");
+            println!("{}", str::from_utf8(rec.qname()).ok().unwrap());
+            assert_eq!(rec.qname(), names[i]);
+            assert_eq!(rec.flags(), flags[i]);
+            assert_eq!(rec.seq().as_bytes(), seqs[i]);
+            assert_eq!(**rec.cigar(), cigars[i]);
+            // fix qual offset
+            let qual: Vec<u8> = quals[i].iter().map(|&q| q - 33).collect();
+            assert_eq!(rec.qual(), &qual[..]);
+            assert_eq!(rec.aux(b"NotAvailableAux"), None);
+        }
+
+        // seek to empty position
+        bam.seek(2, 1, 1).ok().expect("Expected successful seek.");
+        assert!(bam.records().count() == 0);
+    }
+
+    #[test]
+    fn test_fetch() {
+        let mut bam = IndexedReader::from_path(&"test/test.bam").ok().expect("Expected valid index.");
+
+        bam.fetch(b"CHROMOSOME_I:1-2").ok().expect("Expected successful fetch.");
+        assert_eq!(bam.records().count(), 6);
+
+        bam.fetch(b"CHROMOSOME_I").ok().expect("Expected successful fetch.");
+        assert_eq!(bam.records().count(), 6);
+
+        // only the read with the 100kb deletion spans this region
+        bam.fetch(b"CHROMOSOME_I:1,000-2,000").ok().expect("Expected successful fetch.");
+        let qnames: Vec<Vec<u8>> = bam.records().map(|r| r.unwrap().qname().to_owned()).collect();
+        assert_eq!(qnames, vec![b"VI".to_vec()]);
+
+        bam.fetch(b"CHROMOSOME_II").ok().expect("Expected successful fetch.");
+        assert_eq!(bam.records().count(), 0);
+
+        bam.fetch(b"*").ok().expect("Expected successful fetch.");
+        assert_eq!(bam.records().count(), 0);
+
+        match bam.fetch(b"chrUnknown:1-10") {
+            Err(FetchError::UnknownContig(contig)) => assert_eq!(contig, "chrUnknown"),
+            _ => panic!("Expected unknown contig error.")
+        }
+
+        match bam.fetch(b"CHROMOSOME_I:20-10") {
+            Err(FetchError::InvalidRegion(_)) => (),
+            _ => panic!("Expected invalid region error.")
+        }
+    }
+
+    #[test]
+    fn test_fetch_regions() {
+        let mut bam = IndexedReader::from_path(&"test/test.bam").ok().expect("Expected valid index.");
+        let tid = bam.header.tid(b"CHROMOSOME_I").expect("Expected tid.");
+
+        // all reads start at position 1, read VI spans a 100kb deletion
+        let regions = [(tid, 1000, 2000), (tid, 1, 3), (tid, 0, 2), (tid, 500, 600), (2, 0, 100)];
+        let qnames: Vec<Vec<u8>> = bam.fetch_regions(&regions).ok().expect("Expected successful fetch.")
+                                      .map(|r| r.unwrap().qname().to_owned()).collect();
+        let (names, _, _, _, _) = gold();
+        assert_eq!(qnames, names.iter().map(|n| n.to_vec()).collect::<Vec<_>>());
+
+        assert_eq!(bam.fetch_regions(&[]).ok().expect("Expected successful fetch.").count(), 0);
+        assert!(bam.fetch_regions(&[(100, 0, 10)]).is_err());
+        assert!(bam.fetch_regions(&[(tid, 10, 10)]).is_err());
+    }
+
+    #[test]
+    fn test_fetch_unplaced() {
+        let mut bam = IndexedReader::from_path(&"test/test.bam").ok().expect("Expected valid index.");
+        assert_eq!(bam.unplaced_count(), 0);
+        bam.fetch_unplaced().ok().expect("Expected successful fetch.");
+        assert_eq!(bam.records().count(), 0);
+    }
+
+    #[test]
+    fn test_read_indexed_explicit_index() {
+        let tmp = tempdir::TempDir::new("rust-htslib").ok().expect("Cannot create temp dir");
+        let idxpath = tmp.path().join("other.bai");
+        fs::copy("test/test.bam.bai", &idxpath).ok().expect("Failed to copy index.");
+
+        let mut bam = IndexedReader::from_path_and_index(&"test/test.bam", &idxpath).ok().expect("Expected valid index.");
+        let tid = bam.header.tid(b"CHROMOSOME_I").expect("Expected tid.");
+        bam.seek(tid, 0, 2).ok().expect("Expected successful seek.");
+        assert_eq!(bam.records().count(), 6);
+
+        // the index format is determined from the extension
+        let idxpath = tmp.path().join("index");
+        fs::copy("test/test.bam.bai", &idxpath).ok().expect("Failed to copy index.");
+        assert!(IndexedReader::from_path_and_index(&"test/test.bam", &idxpath).is_err());
+
+        assert!(IndexedReader::from_path_and_index(&"test/test.bam", tmp.path().join("missing.bai")).is_err());
+        assert!(IndexedReader::from_path_and_index(&"test/test.bam", &"test/test.bam").is_err());
+
+        tmp.close().ok().expect("Failed to delete temp dir");
+    }
+
+    #[test]
+    fn test_build_index() {
+        let tmp = tempdir::TempDir::new("rust-htslib").ok().expect("Cannot create temp dir");
+        let bampath = tmp.path().join("test.bam");
+
+        for &idx_type in [index::IndexType::Bai, index::IndexType::Csi { min_shift: 14 }].iter() {
+            fs::copy("test/test.bam", &bampath).ok().expect("Failed to copy BAM.");
+            index::build(&bampath, idx_type).ok().expect("Failed to build index.");
+            let ext = if idx_type == index::IndexType::Bai { "bam.bai" } else { "bam.csi" };
+            let idxpath = bampath.with_extension(ext);
+            assert!(idxpath.exists());
+
+            let mut bam = IndexedReader::from_path_and_index(&bampath, &idxpath).ok().expect("Expected valid index.");
+            bam.seek(0, 0, 2).ok().expect("Expected successful seek.");
+            assert_eq!(bam.records().count(), 6);
+            fs::remove_file(&idxpath).ok().expect("Failed to remove index.");
+        }
+
+        assert!(index::build(tmp.path().join("missing.bam"), index::IndexType::Bai).is_err());
+
+        tmp.close().ok().expect("Failed to delete temp dir");
+    }
+
+    #[test]
+    fn test_write_index() {
+        let (names, _, seqs, quals, cigars) = gold();
+
+        let tmp = tempdir::TempDir::new("rust-htslib").ok().expect("Cannot create temp dir");
+        let bampath = tmp.path().join("test.bam");
+
+        for &idx_type in [index::IndexType::Bai, index::IndexType::Csi { min_shift: 14 }].iter() {
+            {
+                let mut bam = Writer::from_path(
+                    &bampath,
+                    Header::new().push_record(
+                        HeaderRecord::new(b"SQ").push_tag(b"SN", &"chr1")
+                                                .push_tag(b"LN", &15072423)
+                    )
+                ).ok().expect("Error opening file.");
+                bam.build_index(idx_type).ok().expect("Failed to initialize index.");
+                assert!(bam.set_threads(2).is_err());
+
+                for i in 0 .. 10000 {
+                    let mut rec = record::Record::new();
+                    let idx = i % names.len();
+                    rec.set(names[idx], &cigars[idx], seqs[idx], quals[idx]);
+                    rec.set_pos(i as i32 * 10);
+                    bam.write(&rec).ok().expect("Failed to write record.");
+                }
+                // unplaced unmapped reads at the end
+                for i in 0 .. 3 {
+                    let mut rec = record::Record::new();
+                    rec.set(names[i], &[], seqs[i], quals[i]);
+                    rec.set_tid(-1);
+                    rec.set_pos(-1);
+                    rec.set_unmapped();
+                    bam.write(&rec).ok().expect("Failed to write record.");
+                }
+                bam.close().ok().expect("Failed to close file.");
+            }
+
+            let mut bam = IndexedReader::from_path(&bampath).ok().expect("Expected valid index.");
+            // reads span 101bp, except for every sixth, which spans 100100bp
+            bam.seek(0, 50000, 50010).ok().expect("Expected successful seek.");
+            let expected = (0..10000).filter(|&i| {
+                let end = i * 10 + if i % names.len() == 5 { 100100 } else { 101 };
+                i * 10 < 50010 && end > 50000
+            }).count();
+            assert_eq!(bam.records().count(), expected);
+
+            let stats = bam.idxstats().ok().expect("Expected index statistics.");
+            assert_eq!((stats[0].mapped, stats[0].unmapped), (10000, 0));
+            assert_eq!(bam.unplaced_count(), 3);
+            bam.fetch_unplaced().ok().expect("Expected successful fetch.");
+            assert_eq!(bam.records().count(), 3);
+
+            let idxpath = bampath.with_extension(if idx_type == index::IndexType::Bai { "bam.bai" } else { "bam.csi" });
+            fs::remove_file(&idxpath).ok().expect("Failed to remove index.");
+        }
+
+        tmp.close().ok().expect("Failed to delete temp dir");
+    }
+
+    #[test]
+    fn test_write_index_unsorted() {
+        let (names, _, seqs, quals, cigars) = gold();
+
+        let tmp = tempdir::TempDir::new("rust-htslib").ok().expect("Cannot create temp dir");
+        let bampath = tmp.path().join("test.bam");
+        let mut bam = Writer::from_path(
+            &bampath,
+            Header::new().push_record(
+                HeaderRecord::new(b"SQ").push_tag(b"SN", &"chr1")
+                                        .push_tag(b"LN", &15072423)
+            )
+        ).ok().expect("Error opening file.");
+        bam.build_index(index::IndexType::Bai).ok().expect("Failed to initialize index.");
+
+        let mut rec = record::Record::new();
+        rec.set(names[0], &cigars[0], seqs[0], quals[0]);
+        rec.set_pos(100);
+        bam.write(&rec).ok().expect("Failed to write record.");
+        rec.set_pos(10);
+        match bam.write(&rec) {
+            Err(WriteError::Unsorted) => (),
+            _ => panic!("Expected unsorted error.")
+        }
+        assert!(bam.build_index(index::IndexType::Bai).is_err());
+    }
+
+    #[test]
+    fn test_idxstats() {
+        let bam = IndexedReader::from_path(&"test/test.bam").ok().expect("Expected valid index.");
+        let stats = bam.idxstats().ok().expect("Expected index statistics.");
+        assert_eq!(stats.len(), 5);
+        assert_eq!(stats[0], IndexStats { tid: 0, name: b"CHROMOSOME_I".to_vec(), len: 15072423, mapped: 6, unmapped: 0 });
+        for s in &stats[1..] {
+            assert_eq!((s.mapped, s.unmapped), (0, 0));
+            assert_eq!(s.len, bam.header.target_len(s.tid).unwrap());
+        }
+        assert_eq!(bam.unplaced_count(), 0);
+    }
+
+    #[test]
+    fn test_sort() {
+        let (names, _, seqs, quals, cigars) = gold();
+
+        let tmp = tempdir::TempDir::new("rust-htslib").ok().expect("Cannot create temp dir");
+        let unsorted = tmp.path().join("unsorted.bam");
+        let sorted = tmp.path().join("sorted.bam");
+        {
+            let mut header = Header::new();
+            header.push_record(HeaderRecord::new(b"HD").push_tag(b"VN", &"1.4").push_tag(b"SO", &"unsorted"));
+            for chrom in &["chr1", "chr2"] {
+                header.push_record(HeaderRecord::new(b"SQ").push_tag(b"SN", chrom).push_tag(b"LN", &1000000));
+            }
+            let mut bam = Writer::from_path(&unsorted, &header).ok().expect("Error opening file.");
+            // deterministic shuffle of positions across both contigs, with some unmapped reads
+            for i in 0 .. 1000 {
+                let mut rec = record::Record::new();
+                let idx = i % names.len();
+                rec.set(names[idx], &cigars[idx], seqs[idx], quals[idx]);
+                if i % 97 == 0 {
+                    rec.set_tid(-1);
+                    rec.set_pos(-1);
+                    rec.set_unmapped();
+                } else {
+                    rec.set_tid((i % 2) as i32);
+                    rec.set_pos(((i * 7919) % 1000) as i32 * 100);
+                }
+                bam.write(&rec).ok().expect("Failed to write record.");
+            }
+        }
+
+        // spilling in the foreground and in background threads
+        for &threads in &[1, 2] {
+            let bam = Reader::from_path(&unsorted).ok().expect("Error opening file.");
+            // tiny memory limit, such that temporary files have to be merged in several rounds
+            sort::Sorter::new(sort::SortOrder::Coordinate)
+                .max_mem(1)
+                .threads(threads)
+                .tmp_dir(tmp.path())
+                .sort(&bam, &sorted)
+                .ok().expect("Error sorting.");
+
+            let bam = Reader::from_path(&sorted).ok().expect("Error opening file.");
+            let header = str::from_utf8(bam.header().as_bytes()).unwrap().to_owned();
+            assert!(header.starts_with("@HD\tVN:1.4\tSO:coordinate\n"));
+            let records: Vec<_> = bam.records().map(|r| r.ok().expect("Expected valid record")).collect();
+            assert_eq!(records.len(), 1000);
+            assert_eq!(records.iter().filter(|r| r.tid() == -1).count(), 11);
+            for w in records.windows(2) {
+                assert!(sort::SortOrder::Coordinate.compare(&w[0], &w[1]) != cmp::Ordering::Greater);
+            }
+        }
+    }
+
+    #[test]
+    fn test_sort_queryname() {
+        let (_, _, seqs, quals, cigars) = gold();
+        let names = [&b"r10"[..], &b"r2"[..], &b"r1"[..], &b"r02"[..], &b"q3"[..]];
+
+        let tmp = tempdir::TempDir::new("rust-htslib").ok().expect("Cannot create temp dir");
+        let unsorted = tmp.path().join("unsorted.bam");
+        let sorted = tmp.path().join("sorted.bam");
+        {
+            let mut bam = Writer::from_path(
+                &unsorted,
+                Header::new().push_record(HeaderRecord::new(b"SQ").push_tag(b"SN", &"chr1").push_tag(b"LN", &1000))
+            ).ok().expect("Error opening file.");
+            for (i, name) in names.iter().enumerate() {
+                for &last in &[true, false] {
+                    let mut rec = record::Record::new();
+                    rec.set(name, &cigars[i], seqs[i], quals[i]);
+                    rec.set_paired();
+                    if last { rec.set_last_in_template() } else { rec.set_first_in_template() }
+                    bam.write(&rec).ok().expect("Failed to write record.");
+                }
+            }
+        }
+
+        let bam = Reader::from_path(&unsorted).ok().expect("Error opening file.");
+        sort::Sorter::new(sort::SortOrder::QueryName).sort(&bam, &sorted).ok().expect("Error sorting.");
+
+        let bam = Reader::from_path(&sorted).ok().expect("Error opening file.");
+        assert!(str::from_utf8(bam.header().as_bytes()).unwrap().starts_with("@HD\tVN:1.4\tSO:queryname\n"));
+        let records: Vec<_> = bam.records().map(|r| r.ok().expect("Expected valid record")).collect();
+        let order: Vec<_> = records.iter().map(|r| (r.qname().to_owned(), r.is_first_in_template())).collect();
+        let expected: Vec<_> = [&b"q3"[..], b"r1", b"r02", b"r2", b"r10"].iter()
+                                                                          .flat_map(|n| vec![(n.to_vec(), true), (n.to_vec(), false)])
+                                                                          .collect();
+        assert_eq!(order, expected);
+    }
+
+    #[test]
+    fn test_merge() {
+        let (names, _, seqs, quals, cigars) = gold();
+
+        let tmp = tempdir::TempDir::new("rust-htslib").ok().expect("Cannot create temp dir");
+        let inputs = [
+            (tmp.path().join("a.bam"), ["chr1", "chr2"], "@RG\tID:grp\tSM:a"),
+            (tmp.path().join("b.bam"), ["chr2", "chr3"], "@RG\tID:grp\tSM:b"),
+        ];
+        for &(ref path, ref chroms, rg) in inputs.iter() {
+            let mut header = Header::new();
+            for chrom in chroms {
+                header.push_record(HeaderRecord::new(b"SQ").push_tag(b"SN", chrom).push_tag(b"LN", &1000000));
+            }
+            let mut header = Header::from_bytes(&[&header.to_bytes()[..], rg.as_bytes()].join(&b'\n'));
+            header.push_comment(b"merged");
+            let mut bam = Writer::from_path(path, &header).ok().expect("Error opening file.");
+            for tid in 0..2 {
+                for i in 0..3 {
+                    let mut rec = record::Record::new();
+                    rec.set(names[i], &cigars[i], seqs[i], quals[i]);
+                    rec.set_tid(tid);
+                    rec.set_pos(i as i32 * 1000);
+                    rec.set_mtid(tid);
+                    rec.set_mpos(i as i32 * 1000 + 500);
+                    rec.push_aux(b"RG", &Aux::String(b"grp"));
+                    bam.write(&rec).ok().expect("Failed to write record.");
+                }
+            }
+        }
+
+        let readers = inputs.iter().map(|&(ref path, _, _)| Reader::from_path(path).ok().expect("Error opening file.")).collect();
+        let merged = tmp.path().join("merged.bam");
+        merge::merge(readers, sort::SortOrder::Coordinate, &merged).ok().expect("Error merging.");
+
+        let bam = Reader::from_path(&merged).ok().expect("Error opening file.");
+        assert_eq!(bam.header().target_names(), vec![&b"chr1"[..], b"chr2", b"chr3"]);
+        let header = str::from_utf8(bam.header().as_bytes()).unwrap().to_owned();
+        assert!(header.starts_with("@HD\tVN:1.4\tSO:coordinate\n"));
+        assert!(header.contains("@RG\tID:grp\tSM:a\n@RG\tID:grp-1\tSM:b\n@CO\tmerged"));
+        assert_eq!(header.matches("@CO").count(), 1);
+
+        let records: Vec<_> = bam.records().map(|r| r.ok().expect("Expected valid record")).collect();
+        assert_eq!(records.len(), 12);
+        let tids: Vec<_> = records.iter().map(|r| r.tid()).collect();
+        assert_eq!(tids, vec![0, 0, 0, 1, 1, 1, 1, 1, 1, 2, 2, 2]);
+        for rec in &records {
+            assert_eq!(rec.mtid(), rec.tid());
+        }
+        // on chr2, records of both inputs are interleaved by position, first input first
+        let rgs: Vec<_> = records[3..9].iter().map(|r| r.aux(b"RG").unwrap().string().to_owned()).collect();
+        assert_eq!(rgs, vec![b"grp".to_vec(), b"grp-1".to_vec(), b"grp".to_vec(), b"grp-1".to_vec(), b"grp".to_vec(), b"grp-1".to_vec()]);
+        assert_eq!(records[11].aux(b"RG").unwrap().string(), b"grp-1");
+    }
+
+    #[test]
+    fn test_merge_incompatible_order() {
+        let tmp = tempdir::TempDir::new("rust-htslib").ok().expect("Cannot create temp dir");
+        let mut paths = Vec::new();
+        for (i, chroms) in [["chr1", "chr2"], ["chr2", "chr1"]].iter().enumerate() {
+            let path = tmp.path().join(format!("{}.bam", i));
+            let mut header = Header::new();
+            for chrom in chroms {
+                header.push_record(HeaderRecord::new(b"SQ").push_tag(b"SN", chrom).push_tag(b"LN", &1000));
+            }
+            Writer::from_path(&path, &header).ok().expect("Error opening file.");
+            paths.push(path);
+        }
+        let readers: Vec<_> = paths.iter().map(|path| Reader::from_path(path).ok().expect("Error opening file.")).collect();
+        match merge::Merger::new(readers, sort::SortOrder::Coordinate) {
+            Err(merge::MergeError::IncompatibleOrder(1)) => (),
+            _ => panic!("Expected incompatible order.")
         }
     }
 
-    pub fn target_count(&self) -> u32 {
-        self.inner().n_targets as u32
+    #[test]
+    fn test_merge_sequence_order() {
+        let (names, _, seqs, quals, cigars) = gold();
+        let tmp = tempdir::TempDir::new("rust-htslib").ok().expect("Cannot create temp dir");
+        let mut paths = Vec::new();
+        for (i, chroms) in [["chr2", "chr3"], ["chr1", "chr2"]].iter().enumerate() {
+            let path = tmp.path().join(format!("{}.bam", i));
+            let mut header = Header::new();
+            for chrom in chroms {
+                header.push_record(HeaderRecord::new(b"SQ").push_tag(b"SN", chrom).push_tag(b"LN", &1000));
+            }
+            let mut bam = Writer::from_path(&path, &header).ok().expect("Error opening file.");
+            for tid in 0..2 {
+                let mut rec = record::Record::new();
+                rec.set(names[i], &cigars[i], seqs[i], quals[i]);
+                rec.set_tid(tid);
+                rec.set_pos(100);
+                bam.write(&rec).ok().expect("Failed to write record.");
+            }
+            paths.push(path);
+        }
+        let readers: Vec<_> = paths.iter().map(|path| Reader::from_path(path).ok().expect("Error opening file.")).collect();
+        let merger = merge::Merger::new(readers, sort::SortOrder::Coordinate).ok().expect("Expected compatible order.");
+        let header = HeaderView::new(header_record(&merger.header()));
+        assert_eq!(header.target_names(), vec![&b"chr1"[..], b"chr2", b"chr3"]);
+
+        let records: Vec<_> = merger.records().map(|r| r.ok().expect("Failed to read record.")).collect();
+        let tids: Vec<_> = records.iter().map(|r| r.tid()).collect();
+        assert_eq!(tids, vec![0, 1, 1, 2]);
+        let qnames: Vec<_> = records.iter().map(|r| r.qname().to_owned()).collect();
+        assert_eq!(qnames, vec![names[1].to_owned(), names[0].to_owned(), names[1].to_owned(), names[0].to_owned()]);
     }
 
-    pub fn target_names(&self) -> Vec<&[u8]> {
-        let names = unsafe { slice::from_raw_parts(self.inner().target_name, self.target_count() as usize) };
-        names.iter().map(|name| unsafe { ffi::CStr::from_ptr(*name).to_bytes() }).collect()
+    fn paired_records() -> Vec<record::Record> {
+        // 20 pairs in coordinate order, with mates 300bp apart,
+        // the R1 of the first pair having a supplementary alignment
+        let (_, _, seqs, quals, cigars) = gold();
+        let mut records = Vec::new();
+        for i in 0..20 {
+            for &(first, pos) in &[(true, i * 10), (false, i * 10 + 300)] {
+                let mut rec = record::Record::new();
+                rec.set(format!("pair{}", i).as_bytes(), &cigars[0], seqs[0], quals[0]);
+                rec.set_paired();
+                if first { rec.set_first_in_template() } else { rec.set_last_in_template() }
+                rec.set_pos(pos);
+                if i == 0 && first {
+                    rec.push_aux(b"SA", &Aux::String(b"chr1,1000,+,50M50S,60,0;"));
+                }
+                records.push(rec);
+            }
+        }
+        let mut supp = record::Record::new();
+        supp.set(b"pair0", &cigars[0], seqs[0], quals[0]);
+        supp.set_paired();
+        supp.set_first_in_template();
+        supp.set_supplementary();
+        supp.set_pos(1000);
+        records.push(supp);
+        records.sort_by_key(|rec| rec.pos());
+        records
     }
 
-    pub fn target_len(&self, tid: u32) -> Option<u32> {
-        let inner = unsafe { *self.inner };
-        if (tid as i32) < inner.n_targets {
-            let l: &[u32] = unsafe { slice::from_raw_parts(inner.target_len, inner.n_targets as usize) };
-            Some(l[tid as usize])
+    #[test]
+    fn test_templates() {
+        let mut records = paired_records();
+        records.sort_by(|a, b| sort::SortOrder::QueryName.compare(a, b));
+
+        let templates: Vec<_> = template::Templates::new(records.into_iter().map(Ok))
+                                                   .map(|t| t.ok().expect("Expected grouped input."))
+                                                   .collect();
+        assert_eq!(templates.len(), 20);
+        for t in &templates {
+            assert!(t.is_complete());
+            assert!(t.first().unwrap().is_first_in_template());
+            assert!(t.last().unwrap().is_last_in_template());
+            assert_eq!(t.last().unwrap().pos() - t.first().unwrap().pos(), 300);
         }
-        else {
-            None
+        assert_eq!(templates[0].qname(), b"pair0");
+        assert_eq!(templates[0].supplementary().len(), 1);
+        assert_eq!(templates[0].records.len(), 3);
+    }
+
+    #[test]
+    fn test_templates_ungrouped() {
+        let mut templates = template::Templates::new(paired_records().into_iter().map(Ok));
+        match templates.next() {
+            Some(Err(template::TemplateError::Ungrouped(qname))) => assert_eq!(qname, "pair0"),
+            _ => panic!("Expected error for ungrouped input.")
         }
     }
 
-    /// Retrieve the textual SAM header as bytes
-    pub fn as_bytes<'a>(&'a self) -> &'a [u8] {
-        unsafe{ ffi::CStr::from_ptr((*self.inner).text).to_bytes() }
+    #[test]
+    fn test_templates_collated() {
+        let templates: Vec<_> = template::Collated::new(paired_records().into_iter().map(Ok), 100)
+                                                  .map(|t| t.ok().expect("Expected valid template."))
+                                                  .collect();
+        assert_eq!(templates.len(), 20);
+        assert!(templates.iter().all(|t| t.is_complete()));
+        // the first pair is complete once its supplementary record has been read
+        assert_eq!(templates.last().unwrap().qname(), b"pair0");
+        assert_eq!(templates.last().unwrap().records.len(), 3);
+
+        // a small buffer returns incomplete templates
+        let templates: Vec<_> = template::Collated::new(paired_records().into_iter().map(Ok), 4)
+                                                  .map(|t| t.ok().expect("Expected valid template."))
+                                                  .collect();
+        assert_eq!(templates.iter().map(|t| t.records.len()).sum::<usize>(), 41);
+        assert!(templates.iter().any(|t| !t.is_complete()));
     }
-}
 
-impl Drop for HeaderView {
-    fn drop(&mut self) {
-        if self.owned {
-            unsafe { htslib::bam_hdr_destroy(self.inner); }
-        }
+    #[test]
+    fn test_templates_collated_late_secondary() {
+        let (_, _, seqs, quals, cigars) = gold();
+        let secondary = |pos: i32| {
+            let mut rec = record::Record::new();
+            rec.set(b"pair1", &cigars[0], seqs[0], quals[0]);
+            rec.set_paired();
+            rec.set_first_in_template();
+            rec.set_secondary();
+            rec.set_pos(pos);
+            rec
+        };
+        // pair1 is complete at position 310, the first secondary record occurs before that
+        let mut records = paired_records();
+        records.push(secondary(100));
+        records.push(secondary(5000));
+        records.sort_by_key(|rec| rec.pos());
+
+        let templates: Vec<_> = template::Collated::new(records.into_iter().map(Ok), 100)
+                                                  .map(|t| t.ok().expect("Expected valid template."))
+                                                  .collect();
+        assert_eq!(templates.len(), 20);
+        assert!(templates.iter().all(|t| t.is_complete()));
+        let pair1 = templates.iter().find(|t| t.qname() == b"pair1").unwrap();
+        assert_eq!(pair1.secondary().len(), 1);
+        assert_eq!(pair1.secondary()[0].pos(), 100);
+        assert_eq!(templates.iter().map(|t| t.records.len()).sum::<usize>(), 42);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    extern crate tempdir;
-    use super::*;
-    use super::record::{Cigar,Aux};
-    use super::header::HeaderRecord;
-    use std::str;
-    use std::path::Path;
+    #[test]
+    fn test_fetch_mate() {
+        let tmp = tempdir::TempDir::new("rust-htslib").ok().expect("Cannot create temp dir");
+        let bampath = tmp.path().join("pairs.bam");
+        let records: Vec<_> = paired_records().into_iter().filter(|rec| !rec.is_supplementary()).map(|mut rec| {
+            let mpos = if rec.is_first_in_template() { rec.pos() + 300 } else { rec.pos() - 300 };
+            rec.set_tid(0);
+            rec.set_mtid(0);
+            rec.set_mpos(mpos);
+            rec
+        }).collect();
+        {
+            let mut bam = Writer::from_path(
+                &bampath,
+                Header::new().push_record(HeaderRecord::new(b"SQ").push_tag(b"SN", &"chr1").push_tag(b"LN", &10000))
+            ).ok().expect("Error opening file.");
+            bam.build_index(index::IndexType::Bai).ok().expect("Failed to initialize index.");
+            for rec in &records {
+                bam.write(rec).ok().expect("Failed to write record.");
+            }
+        }
 
-    fn gold() -> ([&'static [u8]; 6], [u16; 6], [&'static [u8]; 6], [&'static [u8]; 6], [[Cigar; 3]; 6]) {
-        let names = [&b"I"[..], &b"II.14978392"[..], &b"III"[..], &b"IV"[..], &b"V"[..], &b"VI"[..]];
-        let flags = [16u16, 16u16, 16u16, 16u16, 16u16, 2048u16];
-        let seqs = [
-            &b"CCTAGCCCTAACCCTAACCCTAACCCTAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAA"[..],
-            &b"CCTAGCCCTAACCCTAACCCTAACCCTAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAA"[..],
-            &b"CCTAGCCCTAACCCTAACCCTAACCCTAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAA"[..],
-            &b"CCTAGCCCTAACCCTAACCCTAACCCTAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAA"[..],
-            &b"CCTAGCCCTAACCCTAACCCTAACCCTAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAAGCCTAA"[..],
-            &b"ACTAAGCCTAAGCCTAAGCCTAAGCCAATTATCGATTTCTGAAAAAATTATCGAATTTTCTAGAAATTTTGCAAATTTTTTCATAAAATTATCGATTTTA"[..],
-        ];
-        let quals = [
-            &b"#############################@B?8B?BA@@DDBCDDCBC@CDCDCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCC"[..],
-            &b"#############################@B?8B?BA@@DDBCDDCBC@CDCDCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCC"[..],
-            &b"#############################@B?8B?BA@@DDBCDDCBC@CDCDCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCC"[..],
-            &b"#############################@B?8B?BA@@DDBCDDCBC@CDCDCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCC"[..],
-            &b"#############################@B?8B?BA@@DDBCDDCBC@CDCDCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCC"[..],
-            &b"#############################@B?8B?BA@@DDBCDDCBC@CDCDCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCC"[..],
-        ];
-        let cigars = [
-            [Cigar::Match(27), Cigar::Del(1), Cigar::Match(73)],
-            [Cigar::Match(27), Cigar::Del(1), Cigar::Match(73)],
-            [Cigar::Match(27), Cigar::Del(1), Cigar::Match(73)],
-            [Cigar::Match(27), Cigar::Del(1), Cigar::Match(73)],
-            [Cigar::Match(27), Cigar::Del(1), Cigar::Match(73)],
-            [Cigar::Match(27), Cigar::Del(100000), Cigar::Match(73)],
-        ];
-        (names, flags, seqs, quals, cigars)
+        let mut bam = IndexedReader::from_path(&bampath).ok().expect("Expected valid index.");
+        let mate = bam.fetch_mate(&records[0]).ok().expect("Error fetching mate.").expect("Expected mate.");
+        assert_eq!(mate.qname(), records[0].qname());
+        assert_eq!(mate.pos(), 300);
+        assert!(mate.is_last_in_template());
+
+        let mut unpaired = records[0].clone();
+        unpaired.unset_flags();
+        assert!(bam.fetch_mate(&unpaired).ok().expect("Error fetching mate.").is_none());
+
+        let mates = bam.fetch_mates(&records).ok().expect("Error fetching mates.");
+        assert_eq!(mates.len(), records.len());
+        for (rec, mate) in records.iter().zip(mates.iter()) {
+            let mate = mate.as_ref().expect("Expected mate.");
+            assert_eq!(mate.qname(), rec.qname());
+            assert_eq!(mate.pos(), rec.mpos());
+            assert_eq!(mate.mpos(), rec.pos());
+            assert!(mate.is_first_in_template() != rec.is_first_in_template());
+        }
     }
 
     #[test]
-    fn test_read() {
-        let (names, flags, seqs, quals, cigars) = gold();
-        let bam = Reader::from_path(&Path::new("test/test.bam")).ok().expect("Error opening file.");
+    fn test_fixmate() {
+        let mut records = paired_records();
+        records.sort_by(|a, b| sort::SortOrder::QueryName.compare(a, b));
+        for rec in records.iter_mut() {
+            rec.set_tid(0);
+            rec.set_mapq(60);
+            if rec.is_last_in_template() {
+                rec.set_reverse();
+            }
+        }
+        // the first R2 is unmapped
+        records[2].set_unmapped();
+        records[2].set_proper_pair();
+        records[0].set_proper_pair();
+
+        let fixed: Vec<_> = fixmate::FixMates::new(records.into_iter().map(Ok))
+                                              .map(|r| r.ok().expect("Expected valid record."))
+                                              .collect();
+        assert_eq!(fixed.len(), 41);
+        let (_, _, _, quals, _) = gold();
+        let ms = quals[0].iter().filter(|&&q| q >= 15).map(|&q| q as i32).sum::<i32>();
+
+        // pair0 with unmapped R2 and a supplementary record that is left untouched
+        assert_eq!((fixed[0].qname(), fixed[2].qname()), (&b"pair0"[..], &b"pair0"[..]));
+        assert!(fixed[1].is_supplementary());
+        assert!(fixed[1].aux(b"MC").is_none());
+        assert!(fixed[1].aux(b"ms").is_none());
+        assert_eq!(fixed[2].pos(), fixed[0].pos());
+        assert_eq!(fixed[0].mpos(), fixed[0].pos());
+        assert!(fixed[0].is_mate_unmapped());
+        assert!(!fixed[2].is_mate_unmapped());
+        assert!(!fixed[0].is_proper_pair() && !fixed[2].is_proper_pair());
+        assert_eq!((fixed[0].insert_size(), fixed[2].insert_size()), (0, 0));
+        assert!(fixed[0].aux(b"MC").is_none());
+        assert_eq!(fixed[2].aux(b"MC").unwrap().string(), b"27M1D73M");
+
+        // regular pairs, R2 reverse 300bp downstream, spanning 101bp
+        for pair in fixed[3..].chunks(2) {
+            let (r1, r2) = (&pair[0], &pair[1]);
+            assert_eq!(r1.qname(), r2.qname());
+            assert_eq!((r1.mtid(), r1.mpos()), (r2.tid(), r2.pos()));
+            assert_eq!((r2.mtid(), r2.mpos()), (r1.tid(), r1.pos()));
+            assert!(r1.is_mate_reverse() && !r2.is_mate_reverse());
+            assert_eq!(r1.insert_size(), 401);
+            assert_eq!(r2.insert_size(), -401);
+            assert_eq!(r1.aux(b"MQ").unwrap().integer(), 60);
+            assert_eq!(r2.aux(b"MC").unwrap().string(), b"27M1D73M");
+            assert_eq!((r1.aux(b"ms").unwrap().integer(), r2.aux(b"ms").unwrap().integer()), (ms, ms));
+        }
+    }
 
-        for (i, record) in bam.records().enumerate() {
-            let rec = record.ok().expect("Expected valid record");
-            println!("{}", str::from_utf8(rec.qname()).ok().unwrap());
-            assert_eq!(rec.qname(), names[i]);
-            assert_eq!(rec.flags(), flags[i]);
-            assert_eq!(rec.seq().as_bytes(), seqs[i]);
-            assert_eq!(rec.cigar(), cigars[i]);
-            assert_eq!(rec.end_pos(&rec.cigar()), rec.pos() + 100);
-            // fix qual offset
-            let qual: Vec<u8> = quals[i].iter().map(|&q| q - 33).collect();
-            assert_eq!(rec.qual(), &qual[..]);
+    fn markdup_records() -> Vec<record::Record> {
+        let seq = [b'A'; 100];
+        let make = |qname: &[u8], pos: i32, qual: u8, mate: Option<i32>, umi: &[u8]| {
+            let mut rec = record::Record::new();
+            rec.set(qname, &[Cigar::Match(100)], &seq, &[qual; 100]);
+            rec.set_tid(0);
+            rec.set_pos(pos);
+            if let Some(mpos) = mate {
+                rec.set_paired();
+                rec.set_mtid(0);
+                rec.set_mpos(mpos);
+                if mpos > pos {
+                    rec.set_first_in_template();
+                    rec.set_mate_reverse();
+                } else {
+                    rec.set_last_in_template();
+                    rec.set_reverse();
+                }
+                rec.push_aux(b"MC", &Aux::String(b"100M"));
+            }
+            if !umi.is_empty() {
+                rec.push_aux(b"RX", &Aux::String(umi));
+            }
+            rec
+        };
+        let pairs: [(&[u8], u8, &[u8]); 3] = [
+            (b"M1:1:FC:1:1101:1000:2000", 30, b"CCCC"),
+            (b"M1:1:FC:1:1101:1050:2000", 20, b"AAAA"),
+            (b"M1:1:FC:1:1102:5000:5000", 25, b"CCCC"),
+        ];
+        let mut records = Vec::new();
+        for &(qname, qual, umi) in &pairs {
+            records.push(make(qname, 100, qual, Some(301), umi));
+        }
+        records.push(make(b"frag1", 100, 40, None, b""));
+        // reverse mates with 5' end at 400
+        for &(qname, qual, umi) in &pairs {
+            records.push(make(qname, 301, qual, Some(100), umi));
         }
+        records.push(make(b"frag2", 1000, 30, None, b""));
+        records.push(make(b"frag3", 1000, 20, None, b""));
+        records
     }
 
     #[test]
-    fn test_read_sam_header() {
+    fn test_markdup() {
         let bam = Reader::from_path(&"test/test.bam").ok().expect("Error opening file.");
-
-        let true_header = "@SQ\tSN:CHROMOSOME_I\tLN:15072423\n@SQ\tSN:CHROMOSOME_II\tLN:15279345\n@SQ\tSN:CHROMOSOME_III\tLN:13783700\n@SQ\tSN:CHROMOSOME_IV\tLN:17493793\n@SQ\tSN:CHROMOSOME_V\tLN:20924149\n".to_string();
-        let header_text = String::from_utf8(bam.header.as_bytes().to_owned()).unwrap();
-        assert_eq!(header_text, true_header);
+        let mut markdup = markdup::MarkDuplicates::new(markdup_records().into_iter().map(Ok), bam.header());
+        let records: Vec<_> = markdup.by_ref().map(|r| r.ok().expect("Expected valid record.")).collect();
+        assert_eq!(records.len(), 9);
+        let duplicates: Vec<_> = records.iter().map(|r| r.is_duplicate()).collect();
+        assert_eq!(duplicates, vec![false, true, true, true, false, true, true, false, true]);
+
+        let metrics = &markdup.metrics()[&b"Unknown Library"[..]];
+        assert_eq!(*metrics, markdup::DuplicationMetrics {
+            unpaired_reads_examined: 3,
+            read_pairs_examined: 3,
+            secondary_or_supplementary_reads: 0,
+            unmapped_reads: 0,
+            unpaired_read_duplicates: 2,
+            read_pair_duplicates: 2,
+            read_pair_optical_duplicates: 1,
+        });
+        assert!((metrics.percent_duplication() - 6.0 / 9.0).abs() < 1e-9);
+        assert!(metrics.estimated_library_size().is_some());
+
+        let mut out = Vec::new();
+        markdup.write_metrics(&mut out).ok().expect("Error writing metrics.");
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.lines().count(), 2);
+        assert!(out.lines().nth(1).unwrap().starts_with("Unknown Library\t3\t3\t0\t0\t2\t2\t1\t"));
     }
 
-
     #[test]
-    fn test_read_indexed() {
-        let (names, flags, seqs, quals, cigars) = gold();
-        let mut bam = IndexedReader::from_path(&"test/test.bam").ok().expect("Expected valid index.");
-
-        let tid = bam.header.tid(b"CHROMOSOME_I").expect("Expected tid.");
-        assert!(bam.header.target_len(tid).expect("Expected target len.") == 15072423);
-
-        // seek to position containing reads
-        bam.seek(tid, 0, 2).ok().expect("Expected successful seek.");
-        assert!(bam.records().count() == 6);
+    fn test_markdup_umi() {
+        let bam = Reader::from_path(&"test/test.bam").ok().expect("Error opening file.");
+        let records: Vec<_> = markdup::MarkDuplicates::new(markdup_records().into_iter().map(Ok), bam.header())
+                                  .umi_tag(b"RX")
+                                  .map(|r| r.ok().expect("Expected valid record."))
+                                  .collect();
+        let duplicates: Vec<_> = records.iter().map(|r| r.is_duplicate()).collect();
+        assert_eq!(duplicates, vec![false, false, true, false, false, false, true, false, true]);
+    }
 
-        // compare reads
-        bam.seek(tid, 0, 2).ok().expect("Expected successful seek.");
-        for (i, record) in bam.records().enumerate() {
-            let rec = record.ok().expect("Expected valid record");
-            println!("{}", str::from_utf8(rec.qname()).ok().unwrap());
-            assert_eq!(rec.qname(), names[i]);
-            assert_eq!(rec.flags(), flags[i]);
-            assert_eq!(rec.seq().as_bytes(), seqs[i]);
-            assert_eq!(rec.cigar(), cigars[i]);
-            // fix qual offset
-            let qual: Vec<u8> = quals[i].iter().map(|&q| q - 33).collect();
-            assert_eq!(rec.qual(), &qual[..]);
-            assert_eq!(rec.aux(b"NotAvailableAux"), None);
+    #[test]
+    fn test_markdup_unsorted() {
+        let bam = Reader::from_path(&"test/test.bam").ok().expect("Error opening file.");
+        let mut records = markdup_records();
+        records.reverse();
+        let res: Result<Vec<_>, _> = markdup::MarkDuplicates::new(records.into_iter().map(Ok), bam.header()).collect();
+        match res {
+            Err(markdup::MarkDupError::Unsorted) => (),
+            _ => panic!("Expected error for unsorted input.")
         }
-
-        // seek to empty position
-        bam.seek(2, 1, 1).ok().expect("Expected successful seek.");
-        assert!(bam.records().count() == 0);
     }
 
     #[test]
@@ -756,7 +3254,7 @@ mod tests {
         rec.push_aux(b"NM", &Aux::Integer(15));
 
         assert_eq!(rec.qname(), names[0]);
-        assert_eq!(rec.cigar(), cigars[0]);
+        assert_eq!(**rec.cigar(), cigars[0]);
         assert_eq!(rec.seq().as_bytes(), seqs[0]);
         assert_eq!(rec.qual(), quals[0]);
         assert!(rec.is_reverse());
@@ -820,7 +3318,7 @@ mod tests {
                 bam.read(&mut rec).ok().expect("Failed to read record.");
 
                 assert_eq!(rec.qname(), names[i]);
-                assert_eq!(rec.cigar(), cigars[i]);
+                assert_eq!(**rec.cigar(), cigars[i]);
                 assert_eq!(rec.seq().as_bytes(), seqs[i]);
                 assert_eq!(rec.qual(), quals[i]);
                 assert_eq!(rec.aux(b"NM").unwrap(), Aux::Integer(15));
@@ -869,7 +3367,7 @@ mod tests {
 
                 assert_eq!(rec.pos(), i as i32);
                 assert_eq!(rec.qname(), names[idx]);
-                assert_eq!(rec.cigar(), cigars[idx]);
+                assert_eq!(**rec.cigar(), cigars[idx]);
                 assert_eq!(rec.seq().as_bytes(), seqs[idx]);
                 assert_eq!(rec.qual(), quals[idx]);
                 assert_eq!(rec.aux(b"NM").unwrap(), Aux::Integer(15));
@@ -881,6 +3379,146 @@ mod tests {
 
 
 
+    /// Write a coordinate-sorted and indexed BAM file with 10000 records, which spans
+    /// many BGZF blocks.
+    fn write_threading_fixture(path: &Path) {
+        let (names, flags, seqs, quals, cigars) = gold();
+        let mut bam = Writer::from_path(
+            path,
+            Header::new().push_record(
+                HeaderRecord::new(b"SQ").push_tag(b"SN", &"chr1")
+                                        .push_tag(b"LN", &15072423)
+            )
+        ).ok().expect("Error opening file.");
+        bam.build_index(index::IndexType::Bai).ok().expect("Failed to build index.");
+
+        for i in 0 .. 10000 {
+            let mut rec = record::Record::new();
+            let idx = i % names.len();
+            rec.set(names[idx], &cigars[idx], seqs[idx], quals[idx]);
+            rec.set_flags(flags[idx]);
+            rec.set_tid(0);
+            rec.set_pos(i as i32);
+            rec.set_mapq((i % 60) as u8);
+            rec.set_mtid(-1);
+            rec.set_mpos(-1);
+
+            bam.write(&rec).ok().expect("Failed to write record.");
+        }
+        bam.close().ok().expect("Failed to close file.");
+    }
+
+    /// Check that both readers return the same records, and return their number.
+    fn assert_same_records<R: Read, S: Read>(single: &R, threaded: &S) -> usize {
+        let mut n = 0;
+        let mut rec = record::Record::new();
+        for expected in single.records() {
+            let expected = expected.ok().expect("Failed to read record.");
+            threaded.read(&mut rec).ok().expect("Failed to read record.");
+            assert_eq!(rec.tid(), expected.tid());
+            assert_eq!(rec.pos(), expected.pos());
+            assert_eq!(rec.flags(), expected.flags());
+            assert_eq!(rec.mapq(), expected.mapq());
+            assert_eq!(rec.mtid(), expected.mtid());
+            assert_eq!(rec.mpos(), expected.mpos());
+            assert_eq!(rec.insert_size(), expected.insert_size());
+            assert_eq!(rec.qname(), expected.qname());
+            assert_eq!(rec.cigar(), expected.cigar());
+            assert_eq!(rec.seq().as_bytes(), expected.seq().as_bytes());
+            assert_eq!(rec.qual(), expected.qual());
+            n += 1;
+        }
+        assert!(threaded.read(&mut rec).err().expect("Expected end of file.").is_eof());
+        n
+    }
+
+    #[test]
+    fn test_read_threaded() {
+        let tmp = tempdir::TempDir::new("rust-htslib").ok().expect("Cannot create temp dir");
+        let bampath = tmp.path().join("test.bam");
+        write_threading_fixture(&bampath);
+
+        {
+            let single = Reader::from_path(&bampath).ok().expect("Error opening file.");
+            let mut threaded = Reader::from_path(&bampath).ok().expect("Error opening file.");
+            threaded.set_threads(4).ok().expect("Error setting threads.");
+            assert_eq!(assert_same_records(&single, &threaded), 10000);
+        }
+
+        {
+            let single = Reader::from_path(&bampath).ok().expect("Error opening file.");
+            let mut threaded = Reader::from_path(&bampath).ok().expect("Error opening file.");
+            threaded.set_threads(4).ok().expect("Error setting threads.");
+
+            let expected = collect_pileups(single.pileup(), |p| (p.tid(), p.pos(), p.depth()));
+            let pileups = collect_pileups(threaded.pileup(), |p| (p.tid(), p.pos(), p.depth()));
+            assert!(!expected.is_empty());
+            assert_eq!(pileups, expected);
+        }
+
+        {
+            let single = SamReader::from_path(&"test/test.sam").ok().expect("Error opening file.");
+            let mut threaded = SamReader::from_path(&"test/test.sam").ok().expect("Error opening file.");
+            threaded.set_threads(2).ok().expect("Error setting threads.");
+            assert!(assert_same_records(&single, &threaded) > 0);
+        }
+
+        {
+            let mut bam = Reader::from_path(&bampath).ok().expect("Error opening file.");
+            assert!(bam.set_threads(0).is_err());
+            // stopping the background thread early must not block
+            bam.set_threads(1).ok().expect("Error setting threads.");
+            let mut rec = record::Record::new();
+            bam.read(&mut rec).ok().expect("Failed to read record.");
+        }
+
+        tmp.close().ok().expect("Failed to delete temp dir");
+    }
+
+    #[test]
+    fn test_read_indexed_threaded() {
+        let tmp = tempdir::TempDir::new("rust-htslib").ok().expect("Cannot create temp dir");
+        let bampath = tmp.path().join("test.bam");
+        write_threading_fixture(&bampath);
+
+        let mut single = IndexedReader::from_path(&bampath).ok().expect("Expected valid index.");
+        let mut threaded = IndexedReader::from_path(&bampath).ok().expect("Expected valid index.");
+        threaded.set_threads(2).ok().expect("Error setting threads.");
+
+        // every sixth record has a long deletion (see gold)
+        let end_pos = |i: u32| i + if i % 6 == 5 { 100101 } else { 101 };
+        for &(beg, end) in [(0, 2), (5000, 5001), (0, 15072423)].iter() {
+            let n = (0..10000).filter(|&i| i < end && end_pos(i) > beg).count();
+            single.seek(0, beg, end).ok().expect("Expected successful seek.");
+            threaded.seek(0, beg, end).ok().expect("Expected successful seek.");
+            assert_eq!(assert_same_records(&single, &threaded), n);
+
+            single.seek(0, beg, end).ok().expect("Expected successful seek.");
+            threaded.seek(0, beg, end).ok().expect("Expected successful seek.");
+            let expected = collect_pileups(single.pileup(), |p| (p.pos(), p.depth()));
+            let pileups = collect_pileups(threaded.pileup(), |p| (p.pos(), p.depth()));
+            assert_eq!(pileups, expected);
+        }
+
+        // seeking while the background thread is still reading
+        threaded.seek(0, 0, 15072423).ok().expect("Expected successful seek.");
+        let mut rec = record::Record::new();
+        threaded.read(&mut rec).ok().expect("Failed to read record.");
+        threaded.seek(0, 9000, 9001).ok().expect("Expected successful seek.");
+        threaded.read(&mut rec).ok().expect("Failed to read record.");
+        assert!(rec.pos() <= 9000 && rec.end_pos() > 9000);
+
+        tmp.close().ok().expect("Failed to delete temp dir");
+    }
+
+    #[test]
+    fn test_reader_rejects_sam() {
+        match Reader::from_path(&"test/test.sam") {
+            Err(ReaderPathError::BGZFError(_)) => (),
+            _ => panic!("Expected error when opening SAM file with BAM reader.")
+        }
+    }
+
     #[test]
     fn test_copy_template() {
         // Verify that BAM headers are transmitted correctly when using an existing BAM as a template for headers.
@@ -919,9 +3557,9 @@ mod tests {
         let (_, _, seqs, quals, _) = gold();
 
         let bam = Reader::from_path(&"test/test.bam").ok().expect("Error opening file.");
-        let pileups = bam.pileup();
-        for pileup in pileups.take(26) {
-            let _pileup = pileup.ok().expect("Expected successful pileup.");
+        let mut pileups = bam.pileup();
+        for _ in 0..26 {
+            let _pileup = pileups.next().unwrap().ok().expect("Expected successful pileup.");
             let pos = _pileup.pos() as usize;
             assert_eq!(_pileup.depth(), 6);
             assert!(_pileup.tid() == 0);
@@ -933,4 +3571,301 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_pileup_alignment() {
+        let (_, _, seqs, quals, _) = gold();
+
+        let bam = Reader::from_path(&"test/test.bam").ok().expect("Error opening file.");
+        let mut pileups = bam.pileup();
+        for _ in 0..102 {
+            let pileup = pileups.next().unwrap().ok().expect("Expected successful pileup.");
+            let pos = pileup.pos();
+            for (i, a) in pileup.alignments().enumerate() {
+                assert_eq!(a.is_head(), pos == 1);
+                assert!(!a.is_refskip());
+                assert_eq!(a.insertion(), None);
+                if pos == 28 {
+                    assert!(a.is_del());
+                    assert_eq!(a.base(), None);
+                    assert_eq!(a.qual(), None);
+                } else if !a.is_del() {
+                    assert_eq!(a.base(), Some(seqs[i][a.qpos()]));
+                    assert_eq!(a.qual(), Some(quals[i][a.qpos()] - 33));
+                }
+            }
+            if pos == 101 {
+                assert_eq!(pileup.alignments().filter(|a| a.is_tail()).count(), 5);
+            }
+        }
+    }
+
+    #[test]
+    fn test_pileup_without_sequence() {
+        let tmp = tempdir::TempDir::new("rust-htslib").ok().expect("Cannot create temp dir");
+        let bampath = tmp.path().join("test.bam");
+        {
+            let mut bam = Writer::from_path(
+                &bampath,
+                Header::new().push_record(
+                    HeaderRecord::new(b"SQ").push_tag(b"SN", &"chr1")
+                                            .push_tag(b"LN", &100)
+                )
+            ).ok().expect("Error opening file.");
+            let cigar = [Cigar::Match(5), Cigar::Ins(2), Cigar::Match(5)];
+            // the second record has SEQ and QUAL `*`
+            for &(qname, seq, qual) in [(&b"r1"[..], &b"ACGTACCACGTA"[..], &[30u8; 12][..]), (&b"r2"[..], &b""[..], &b""[..])].iter() {
+                let mut rec = record::Record::new();
+                rec.set(qname, &cigar, seq, qual);
+                rec.set_tid(0);
+                rec.set_pos(0);
+                rec.set_mtid(-1);
+                rec.set_mpos(-1);
+                bam.write(&rec).ok().expect("Failed to write record.");
+            }
+        }
+
+        let bam = Reader::from_path(&bampath).ok().expect("Error opening file.");
+        let mut pileups = bam.pileup();
+        let mut n = 0;
+        while let Some(p) = pileups.next() {
+            let p = p.ok().expect("Expected successful pileup.");
+            assert_eq!(p.depth(), 2);
+            for a in p.alignments() {
+                if a.record().qname() == b"r2" {
+                    assert_eq!(a.base(), None);
+                    assert_eq!(a.qual(), None);
+                    assert_eq!(a.insertion(), None);
+                } else {
+                    assert!(a.base().is_some());
+                    assert_eq!(a.qual(), Some(30));
+                    assert_eq!(a.insertion().is_some(), p.pos() == 4);
+                }
+            }
+            let counts = p.allele_counts(20);
+            assert_eq!(counts.total().depth(), 1);
+            assert_eq!(counts.low_quality().depth(), 0);
+            n += 1;
+        }
+        assert_eq!(n, 10);
+
+        // records without sequence pass the base quality threshold
+        let mut builder = pileup::PileupBuilder::new();
+        builder.min_baseq(20);
+        let bam = Reader::from_path(&bampath).ok().expect("Error opening file.");
+        assert_eq!(collect_pileups(builder.pileup(&bam), |p| p.depth()), vec![2; 10]);
+
+        tmp.close().ok().expect("Failed to delete temp dir");
+    }
+
+    #[test]
+    fn test_multi_pileup() {
+        // readers of different types can be combined
+        let tumor = Reader::from_path(&"test/test.bam").ok().expect("Error opening file.");
+        let mut normal = IndexedReader::from_path(&"test/test.bam").ok().expect("Expected valid index.");
+        let tid = normal.header.tid(b"CHROMOSOME_I").expect("Expected tid.");
+        let len = normal.header.target_len(tid).unwrap();
+        normal.seek(tid, 0, len).ok().expect("Expected successful seek.");
+        let single = Reader::from_path(&"test/test.bam").ok().expect("Error opening file.");
+
+        let expected = collect_pileups(single.pileup(), |p| (p.tid(), p.pos(), p.depth()));
+        let mut pileups = Vec::new();
+        let mut multi_pileups = pileup::MultiPileups::new(&[&tumor, &normal]);
+        while let Some(p) = multi_pileups.next() {
+            let p = p.ok().expect("Expected successful pileup.");
+            assert_eq!(p.pileups().len(), 2);
+            for column in p.pileups() {
+                assert_eq!((column.tid(), column.pos()), (p.tid(), p.pos()));
+                assert_eq!(column.alignments().count() as u32, column.depth());
+            }
+            assert_eq!(p.pileups()[0].depth(), p.pileups()[1].depth());
+            pileups.push((p.tid(), p.pos(), p.pileups()[0].depth()));
+        }
+        assert_eq!(pileups, expected);
+    }
+
+    #[test]
+    fn test_pileup_builder() {
+        let bam = Reader::from_path(&"test/test.bam").ok().expect("Error opening file.");
+        let depths = |builder: &pileup::PileupBuilder| -> Vec<u32> {
+            let bam = Reader::from_path(&"test/test.bam").ok().expect("Error opening file.");
+            collect_pileups(builder.pileup(&bam), |p| p.depth())
+        };
+        let unfiltered = collect_pileups(bam.pileup(), |p| p.depth());
+
+        // all records are reverse, with mapping quality 1
+        let mut builder = pileup::PileupBuilder::new();
+        assert_eq!(depths(&builder), unfiltered);
+        builder.overlaps(true).required_flags(0x10);
+        assert_eq!(depths(&builder), unfiltered);
+        builder.min_mapq(2);
+        assert!(depths(&builder).is_empty());
+        let mut builder = pileup::PileupBuilder::new();
+        builder.excluded_flags(0x10);
+        assert!(depths(&builder).is_empty());
+
+        let mut builder = pileup::PileupBuilder::new();
+        builder.filter(|record| record.qname() == b"I");
+        let filtered = depths(&builder);
+        assert!(!filtered.is_empty() && filtered.iter().all(|&depth| depth == 1));
+
+        let mut builder = pileup::PileupBuilder::new();
+        builder.min_baseq(10);
+        let bam = Reader::from_path(&"test/test.bam").ok().expect("Error opening file.");
+        let mut total = 0;
+        let mut pileups = builder.pileup(&bam);
+        while let Some(p) = pileups.next() {
+            let p = p.ok().expect("Expected successful pileup.");
+            assert_eq!(p.alignments().count() as u32, p.depth());
+            // deletions (starting at position 28) are not filtered
+            if p.pos() < 28 {
+                for a in p.alignments() {
+                    assert!(a.record().qual()[a.qpos()] >= 10);
+                }
+            }
+            total += p.depth();
+        }
+        assert!(total < unfiltered.iter().sum());
+    }
+
+    #[test]
+    fn test_pileup_region() {
+        let mut bam = IndexedReader::from_path(&"test/test.bam").ok().expect("Expected valid index.");
+        let positions = collect_pileups(bam.pileup_region(0, 50, 60).ok().expect("Expected successful seek."), |p| {
+            assert_eq!(p.depth(), 6);
+            p.pos()
+        });
+        assert_eq!(positions, (50..60).collect::<Vec<_>>());
+
+        // only the read with the long deletion reaches this region, it ends at 100101
+        let mut builder = pileup::PileupBuilder::new();
+        let pileups = collect_pileups(builder.region_pileup(&mut bam, 0, 100095, 100110)
+                                             .ok().expect("Expected successful seek."),
+                                      |p| (p.pos(), p.depth()));
+        assert_eq!(pileups, (100095..100101).map(|pos| (pos, 1)).collect::<Vec<_>>());
+
+        builder.zero_depth(true);
+        let pileups = collect_pileups(builder.region_pileup(&mut bam, 0, 100095, 100110)
+                                             .ok().expect("Expected successful seek."),
+                                      |p| {
+                                          assert_eq!(p.alignments().count() as u32, p.depth());
+                                          (p.pos(), p.depth())
+                                      });
+        assert_eq!(pileups, (100095..100110).map(|pos| (pos, if pos < 100101 { 1 } else { 0 })).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_allele_counts() {
+        let (_, _, seqs, _, _) = gold();
+        let mut bam = IndexedReader::from_path(&"test/test.bam").ok().expect("Expected valid index.");
+
+        // the first 29 bases have quality 2, five reads are reverse, the 6th is forward
+        let counts: Vec<_> = bam.pileup_region(0, 20, 30).ok().expect("Expected successful seek.")
+                                .allele_counts(10)
+                                .map(|c| c.ok().expect("Expected successful pileup."))
+                                .collect();
+        assert_eq!(counts.len(), 10);
+        for c in &counts[..8] {
+            let qpos = c.pos as usize - 1;
+            assert_eq!(c.total().depth(), 0);
+            assert_eq!(c.low_quality_reverse.base(seqs[0][qpos]), 5);
+            assert_eq!(c.low_quality_forward.base(seqs[5][qpos]), 1);
+            assert_eq!(c.low_quality().depth(), 6);
+        }
+        assert_eq!((counts[8].pos, counts[8].forward.del, counts[8].reverse.del), (28, 1, 5));
+        assert_eq!(counts[8].total().depth(), 6);
+        assert_eq!(counts[9].forward.del, 1);
+        assert_eq!(counts[9].low_quality_reverse.depth(), 5);
+        assert!(counts.iter().all(|c| c.total().ins == 0));
+
+        let c = bam.pileup_region(0, 20, 21).ok().expect("Expected successful seek.")
+                   .allele_counts(0)
+                   .next().unwrap().ok().expect("Expected successful pileup.");
+        assert_eq!(c.low_quality(), pileup::Counts::default());
+        assert_eq!(c.total().depth(), 6);
+        assert_eq!(c.total().base(seqs[0][19]), 5 + if seqs[5][19] == seqs[0][19] { 1 } else { 0 });
+    }
+
+    #[test]
+    fn test_coverage() {
+        let mut bam = IndexedReader::from_path(&"test/test.bam").ok().expect("Expected valid index.");
+        let builder = coverage::CoverageBuilder::new();
+
+        // deletions are not counted, the 6th read resumes after its long deletion
+        let depths: Vec<_> = builder.region_depths(&mut bam, &[(0, 0, 200)])
+                                    .ok().expect("Expected valid region.")
+                                    .map(|d| { let d = d.ok().expect("Expected depth."); (d.beg, d.end, d.depth) })
+                                    .collect();
+        assert_eq!(depths, [(0, 1, 0), (1, 28, 6), (28, 29, 0), (29, 102, 5), (102, 200, 0)]);
+
+        let per_base: Vec<_> = builder.region_depths(&mut bam, &[(0, 20, 30)])
+                                      .ok().expect("Expected valid region.")
+                                      .per_base()
+                                      .map(|d| d.ok().expect("Expected depth.").depth)
+                                      .collect();
+        assert_eq!(per_base, [6, 6, 6, 6, 6, 6, 6, 6, 0, 5]);
+
+        let windows: Vec<_> = builder.region_depths(&mut bam, &[(0, 0, 120), (0, 100000, 100030)])
+                                     .ok().expect("Expected valid region.")
+                                     .windows(50, &[1, 6])
+                                     .map(|w| w.ok().expect("Expected window."))
+                                     .collect();
+        let summary: Vec<_> = windows.iter().map(|w| (w.beg, w.end, w.covered.clone())).collect();
+        assert_eq!(summary, [
+            (0, 50, vec![48, 27]),
+            (50, 100, vec![50, 0]),
+            (100, 120, vec![2, 0]),
+            (100000, 100030, vec![2, 0])
+        ]);
+        assert_eq!(windows[0].mean, 267.0 / 50.0);
+
+        let mut builder = coverage::CoverageBuilder::new();
+        builder.min_mapq(2);
+        let mut out = Vec::new();
+        builder.region_depths(&mut bam, &[(0, 0, 200)]).ok().expect("Expected valid region.")
+               .write_bedgraph(&mut out).ok().expect("Expected successful write.");
+        assert_eq!(out, b"CHROMOSOME_I\t0\t200\t0\n");
+
+        assert!(builder.region_depths(&mut bam, &[(0, 200, 100)]).is_err());
+    }
+
+    #[test]
+    fn test_coverage_overlaps() {
+        let tmp = tempdir::TempDir::new("rust-htslib").ok().expect("Cannot create temp dir");
+        let bampath = tmp.path().join("pair.bam");
+        let (_, _, seqs, quals, cigars) = gold();
+        {
+            let template = Reader::from_path(&"test/test.bam").ok().expect("Error opening file.");
+            let mut bam = Writer::from_path(&bampath, &Header::from_template(template.header()))
+                                 .ok().expect("Error opening file.");
+            for &(first, pos, mpos) in &[(true, 1000, 1050), (false, 1050, 1000)] {
+                let mut rec = record::Record::new();
+                rec.set(b"pair", &cigars[0], seqs[0], quals[0]);
+                rec.set_paired();
+                if first { rec.set_first_in_template() } else { rec.set_last_in_template() }
+                rec.set_tid(0);
+                rec.set_pos(pos);
+                rec.set_mtid(0);
+                rec.set_mpos(mpos);
+                bam.write(&rec).ok().expect("Failed to write record.");
+            }
+        }
+        index::build(&bampath, index::IndexType::Bai).ok().expect("Failed to build index.");
+        let mut bam = IndexedReader::from_path(&bampath).ok().expect("Expected valid index.");
+
+        let depths = |bam: &mut IndexedReader, builder: &coverage::CoverageBuilder| -> Vec<(u32, u32, u32)> {
+            builder.region_depths(bam, &[(0, 1000, 1151)]).ok().expect("Expected valid region.")
+                   .map(|d| { let d = d.ok().expect("Expected depth."); (d.beg, d.end, d.depth) })
+                   .collect()
+        };
+        let mut builder = coverage::CoverageBuilder::new();
+        assert_eq!(depths(&mut bam, &builder), [(1000, 1027, 1), (1027, 1028, 0), (1028, 1151, 1)]);
+        builder.overlaps(false);
+        assert_eq!(depths(&mut bam, &builder), [
+            (1000, 1027, 1), (1027, 1028, 0), (1028, 1050, 1), (1050, 1077, 2),
+            (1077, 1078, 1), (1078, 1101, 2), (1101, 1151, 1)
+        ]);
+
+        tmp.close().ok().expect("Failed to delete temp dir");
+    }
 }
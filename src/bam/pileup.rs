@@ -3,49 +3,114 @@
 // This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use std::marker::PhantomData;
 use std::slice;
-use std::iter;
+use std::ptr;
+use std::ops;
 
 use htslib;
 
+use bam;
 use bam::record;
 
 
 /// Iterator over alignments of a pileup.
-pub type Alignments<'a> = iter::Map<
-    slice::Iter<'a, htslib::bam_pileup1_t>,
-    fn(&'a htslib::bam_pileup1_t) -> Alignment<'a>
->;
+pub struct Alignments<'a> {
+    inner: slice::Iter<'a, htslib::bam_pileup1_t>,
+    min_baseq: u8,
+}
 
 
-/// A pileup over one genomic position.
-pub struct Pileup {
-    inner: *const htslib::bam_pileup1_t,
-    depth: u32,
-    tid: u32,
-    pos: u32,
+impl<'a> Iterator for Alignments<'a> {
+    type Item = Alignment<'a>;
+
+    fn next(&mut self) -> Option<Alignment<'a>> {
+        let min_baseq = self.min_baseq;
+        self.inner.by_ref().find(|plp| passes_baseq(plp, min_baseq)).map(Alignment::new)
+    }
 }
 
 
-impl Pileup {
+/// A pileup over one genomic position. The pileup borrows the iterator it was obtained
+/// from, since htslib reuses the underlying memory for the next position.
+pub struct Pileup<'a> {
+    column: Column,
+    min_baseq: u8,
+    phantom: PhantomData<&'a htslib::bam_pileup1_t>,
+}
+
+
+impl<'a> Pileup<'a> {
+    fn new(column: Column, min_baseq: u8) -> Self {
+        Pileup { column: column, min_baseq: min_baseq, phantom: PhantomData }
+    }
+
     pub fn tid(&self) -> u32 {
-        self.tid
+        self.column.tid
     }
 
     pub fn pos(&self) -> u32 {
-        self.pos
+        self.column.pos
     }
 
+    /// Number of alignments, without bases below the minimum base quality.
     pub fn depth(&self) -> u32 {
-        self.depth
+        if self.min_baseq == 0 {
+            self.column.depth
+        } else {
+            self.alignments().count() as u32
+        }
+    }
+
+    pub fn alignments(&self) -> Alignments<'a> {
+        Alignments { inner: self.inner().iter(), min_baseq: self.min_baseq }
     }
 
-    pub fn alignments(&self) -> Alignments {
-        self.inner().iter().map(Alignment::new)
+    /// Count the alleles of this position by strand. Bases with a quality below `min_baseq`
+    /// are counted separately (pass 0 to not distinguish them).
+    pub fn allele_counts(&self, min_baseq: u8) -> AlleleCounts {
+        let mut counts = AlleleCounts {
+            tid: self.tid(),
+            pos: self.pos(),
+            forward: Counts::default(),
+            reverse: Counts::default(),
+            low_quality_forward: Counts::default(),
+            low_quality_reverse: Counts::default(),
+        };
+        for alignment in self.alignments() {
+            // records without sequence do not contribute alleles
+            if alignment.is_refskip() || (!alignment.is_del() && alignment.base().is_none()) {
+                continue;
+            }
+            let reverse = alignment.record().is_reverse();
+            let low_quality = alignment.qual().map_or(false, |qual| qual < min_baseq);
+            let c = match (low_quality, reverse) {
+                (false, false) => &mut counts.forward,
+                (false, true)  => &mut counts.reverse,
+                (true, false)  => &mut counts.low_quality_forward,
+                (true, true)   => &mut counts.low_quality_reverse
+            };
+            match alignment.base() {
+                Some(b'A') => c.a += 1,
+                Some(b'C') => c.c += 1,
+                Some(b'G') => c.g += 1,
+                Some(b'T') => c.t += 1,
+                Some(_)    => c.n += 1,
+                None       => c.del += 1
+            }
+            if let Indel::Ins(_) = alignment.indel() {
+                c.ins += 1;
+            }
+        }
+        counts
     }
 
-    fn inner(&self) -> &[htslib::bam_pileup1_t] {
-        unsafe { slice::from_raw_parts(self.inner as *mut htslib::bam_pileup1_t, self.depth as usize) }
+    fn inner(&self) -> &'a [htslib::bam_pileup1_t] {
+        if self.column.depth == 0 {
+            // htslib does not provide a valid pointer for empty pileups
+            return &[];
+        }
+        unsafe { slice::from_raw_parts(self.column.inner, self.column.depth as usize) }
     }
 }
 
@@ -53,12 +118,13 @@ impl Pileup {
 /// An aligned read in a pileup.
 pub struct Alignment<'a> {
     inner: &'a htslib::bam_pileup1_t,
+    record: record::Record,
 }
 
 
 impl<'a> Alignment<'a> {
     pub fn new(inner: &'a htslib::bam_pileup1_t) -> Self {
-        Alignment { inner: inner }
+        Alignment { inner: inner, record: record::Record::from_inner(inner.b) }
     }
 
     /// Position within the read.
@@ -75,9 +141,76 @@ impl<'a> Alignment<'a> {
         }
     }
 
-    /// The corresponding record.
-    pub fn record(&self) -> record::Record {
-        record::Record::from_inner(self.inner.b)
+    /// Whether the read has a deletion at this position.
+    pub fn is_del(&self) -> bool {
+        self.flag(IS_DEL)
+    }
+
+    /// Whether the read has a reference skip (e.g. an intron) at this position.
+    pub fn is_refskip(&self) -> bool {
+        self.flag(IS_REFSKIP)
+    }
+
+    /// Whether the read starts at this position.
+    pub fn is_head(&self) -> bool {
+        self.flag(IS_HEAD)
+    }
+
+    /// Whether the read ends at this position.
+    pub fn is_tail(&self) -> bool {
+        self.flag(IS_TAIL)
+    }
+
+    /// Display level of the read in the pileup, as used by `samtools tview`.
+    pub fn level(&self) -> u32 {
+        self.inner.level as u32
+    }
+
+    /// Base of the read at this position, or None in case of a deletion or reference skip,
+    /// or if the record has no sequence (`*` in SAM).
+    pub fn base(&self) -> Option<u8> {
+        if self.is_del() || self.is_refskip() || self.qpos() >= self.record.seq().len() {
+            None
+        } else {
+            Some(self.record.seq()[self.qpos()])
+        }
+    }
+
+    /// Base quality of the read at this position, or None in case of a deletion or
+    /// reference skip, or if the record has no sequence (`*` in SAM).
+    pub fn qual(&self) -> Option<u8> {
+        if self.is_del() || self.is_refskip() {
+            None
+        } else {
+            self.record.qual().get(self.qpos()).cloned()
+        }
+    }
+
+    /// Bases inserted after this position, or None if there is no insertion or the
+    /// record has no sequence (`*` in SAM).
+    pub fn insertion(&self) -> Option<Vec<u8>> {
+        match self.indel() {
+            Indel::Ins(len) => {
+                let seq = self.record.seq();
+                let (beg, end) = (self.qpos() + 1, self.qpos() + 1 + len as usize);
+                if end > seq.len() {
+                    None
+                } else {
+                    Some((beg..end).map(|i| seq[i]).collect())
+                }
+            },
+            _ => None
+        }
+    }
+
+    /// The corresponding record. It is owned by htslib and only valid as long as the
+    /// pileup, use `clone` to keep a copy.
+    pub fn record(&self) -> &record::Record {
+        &self.record
+    }
+
+    fn flag(&self, flag: u32) -> bool {
+        self.inner.isdel_ishead_istail_isrefskip_isaux & flag != 0
     }
 }
 
@@ -91,58 +224,576 @@ pub enum Indel {
 }
 
 
-/// Iterator over pileups.
-pub struct Pileups {
-    itr: htslib::bam_plp_t,
+/// Allele counts of a pileup position.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AlleleCounts {
+    pub tid: u32,
+    pub pos: u32,
+    pub forward: Counts,
+    pub reverse: Counts,
+    /// Counts of bases below the quality threshold, which are not included in `forward`.
+    pub low_quality_forward: Counts,
+    /// Counts of bases below the quality threshold, which are not included in `reverse`.
+    pub low_quality_reverse: Counts,
 }
 
 
-impl Pileups {
-    pub fn new(itr: htslib::bam_plp_t) -> Self {
-        Pileups { itr: itr }
+impl AlleleCounts {
+    /// Counts of both strands, without bases below the quality threshold.
+    pub fn total(&self) -> Counts {
+        self.forward + self.reverse
     }
 
-    pub fn set_max_depth(&mut self, depth: u32) {
-        unsafe { htslib::bam_plp_set_maxcnt(self.itr, depth as i32); }
+    /// Counts of bases below the quality threshold, of both strands.
+    pub fn low_quality(&self) -> Counts {
+        self.low_quality_forward + self.low_quality_reverse
     }
 }
 
 
-impl Iterator for Pileups {
-    type Item = Result<Pileup, PileupError>;
+/// Number of reads per base, deletion and insertion. Reads with a deletion at a position
+/// are counted in `del`, reads with an insertion after it additionally in `ins`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counts {
+    pub a: u32,
+    pub c: u32,
+    pub g: u32,
+    pub t: u32,
+    pub n: u32,
+    pub del: u32,
+    pub ins: u32,
+}
+
+
+impl Counts {
+    /// Count of the given base (`A`, `C`, `G`, `T` or `N`, case-insensitive).
+    pub fn base(&self, base: u8) -> u32 {
+        match base.to_ascii_uppercase() {
+            b'A' => self.a,
+            b'C' => self.c,
+            b'G' => self.g,
+            b'T' => self.t,
+            b'N' => self.n,
+            _    => 0
+        }
+    }
 
-    fn next(&mut self) -> Option<Result<Pileup, PileupError>> {
+    /// Number of reads with a base or deletion.
+    pub fn depth(&self) -> u32 {
+        self.a + self.c + self.g + self.t + self.n + self.del
+    }
+}
+
+
+impl ops::Add for Counts {
+    type Output = Counts;
+
+    fn add(self, other: Counts) -> Counts {
+        Counts {
+            a: self.a + other.a,
+            c: self.c + other.c,
+            g: self.g + other.g,
+            t: self.t + other.t,
+            n: self.n + other.n,
+            del: self.del + other.del,
+            ins: self.ins + other.ins,
+        }
+    }
+}
+
+
+/// Pileups over the records of a reader. Since each pileup borrows the underlying htslib
+/// memory, this is not an `Iterator`. Instead, pileups are obtained with `next`, e.g.
+///
+/// ```no_run
+/// use rust_htslib::bam;
+/// use rust_htslib::bam::Read;
+///
+/// let bam = bam::Reader::from_path(&"test/test.bam").unwrap();
+/// let mut pileups = bam.pileup();
+/// while let Some(pileup) = pileups.next() {
+///     let pileup = pileup.unwrap();
+///     println!("{}:{} depth {}", pileup.tid(), pileup.pos(), pileup.depth());
+/// }
+/// ```
+///
+/// Pileups and records cannot outlive the position they have been obtained for:
+///
+/// ```compile_fail
+/// use rust_htslib::bam;
+/// use rust_htslib::bam::Read;
+///
+/// let bam = bam::Reader::from_path(&"test/test.bam").unwrap();
+/// let mut pileups = bam.pileup();
+/// let pileup = pileups.next().unwrap().unwrap();
+/// pileups.next();
+/// println!("{}", pileup.depth());
+/// ```
+pub struct Pileups<'a> {
+    itr: htslib::bam_mplp_t,
+    _source: Box<Source<'a>>,
+    min_baseq: u8,
+    region: Option<Region>,
+}
+
+
+/// A pileup column as returned by htslib.
+#[derive(Clone, Copy)]
+struct Column {
+    inner: *const htslib::bam_pileup1_t,
+    depth: u32,
+    tid: u32,
+    pos: u32,
+}
+
+
+/// State of pileups that are clipped to a region.
+struct Region {
+    tid: u32,
+    beg: u32,
+    end: u32,
+    zero_depth: bool,
+    next_pos: u32,
+    pending: Option<Column>,
+    done: bool,
+}
+
+
+impl<'a> Pileups<'a> {
+    /// Create new pileups over the records of the given reader. The reader is borrowed
+    /// until the pileups are dropped.
+    pub fn new<R: bam::Read>(reader: &'a R) -> Self {
+        Self::with_filter(reader, None)
+    }
+
+    fn with_filter<R: bam::Read>(reader: &'a R, filter: Option<&'a Filter<'a>>) -> Self {
+        let mut source = Box::new(Source { reader: reader, filter: filter });
+        let mut data = [&mut *source as *mut Source as *mut ::libc::c_void];
+        let itr = unsafe { htslib::bam_mplp_init(1, Some(read_source), data.as_mut_ptr()) };
+        Pileups { itr: itr, _source: source, min_baseq: 0, region: None }
+    }
+
+    /// Only yield positions within the given region (0-based, half-open), e.g. after
+    /// seeking an `IndexedReader` to it. Reads overlapping the region do not generate
+    /// columns outside of it.
+    ///
+    /// # Arguments
+    ///
+    /// * `tid` - the target id
+    /// * `beg` - the first position
+    /// * `end` - the position after the last one
+    /// * `zero_depth` - whether to also yield positions without reads (with depth zero)
+    pub fn set_region(&mut self, tid: u32, beg: u32, end: u32, zero_depth: bool) {
+        self.region = Some(Region {
+            tid: tid,
+            beg: beg,
+            end: end,
+            zero_depth: zero_depth,
+            next_pos: beg,
+            pending: None,
+            done: false,
+        });
+    }
+
+    pub fn set_max_depth(&mut self, depth: u32) {
+        unsafe { htslib::bam_mplp_set_maxcnt(self.itr, depth as i32) };
+    }
+
+    /// Iterator over the allele counts of the pileups, e.g. of a region obtained via
+    /// `IndexedReader::pileup_region`. See `Pileup::allele_counts`.
+    pub fn allele_counts(self, min_baseq: u8) -> Alleles<'a> {
+        Alleles { pileups: self, min_baseq: min_baseq }
+    }
+
+    /// The next pileup, or None if all records have been consumed. The pileup borrows
+    /// the iterator, i.e. it has to be dropped before advancing.
+    pub fn next<'p>(&'p mut self) -> Option<Result<Pileup<'p>, PileupError>> {
+        let min_baseq = self.min_baseq;
+        self.next_in_region().map(|column| column.map(|column| Pileup::new(column, min_baseq)))
+    }
+
+    /// Next column, clipped to the region if one is set.
+    fn next_in_region(&mut self) -> Option<Result<Column, PileupError>> {
+        if self.region.is_none() {
+            return self.next_column();
+        }
+        loop {
+            let (pending, done) = {
+                let region = self.region.as_ref().unwrap();
+                (region.pending.is_some(), region.done)
+            };
+            if !pending && !done {
+                match self.next_column() {
+                    Some(Ok(column)) => {
+                        let region = self.region.as_mut().unwrap();
+                        if column.tid > region.tid || (column.tid == region.tid && column.pos >= region.end) {
+                            region.done = true;
+                        } else if column.tid == region.tid && column.pos >= region.beg {
+                            region.pending = Some(column);
+                        }
+                        continue;
+                    },
+                    Some(Err(e)) => return Some(Err(e)),
+                    None         => self.region.as_mut().unwrap().done = true
+                }
+            }
+
+            let region = self.region.as_mut().unwrap();
+            if region.zero_depth && region.next_pos < region.end &&
+               region.pending.map_or(true, |column| column.pos > region.next_pos) {
+                let column = Column { inner: ptr::null(), depth: 0, tid: region.tid, pos: region.next_pos };
+                region.next_pos += 1;
+                return Some(Ok(column));
+            }
+            return region.pending.take().map(|column| {
+                region.next_pos = column.pos + 1;
+                Ok(column)
+            });
+        }
+    }
+
+    /// Next pileup column from htslib.
+    fn next_column(&mut self) -> Option<Result<Column, PileupError>> {
         let (mut tid, mut pos, mut depth) = (0i32, 0i32, 0i32);
-        let inner = unsafe {
-            htslib::bam_plp_auto(self.itr, &mut tid, &mut pos, &mut depth)
+        let mut inner = ptr::null();
+        match unsafe { htslib::bam_mplp_auto(self.itr, &mut tid, &mut pos, &mut depth, &mut inner) } {
+            0                         => None,
+            ret if ret < 0 || depth < 0 => Some(Err(PileupError::Some)),
+            _                         => Some(Ok(
+                Column { inner: inner, depth: depth as u32, tid: tid as u32, pos: pos as u32 }
+            ))
+        }
+    }
+}
+
+
+impl<'a> Drop for Pileups<'a> {
+    fn drop(&mut self) {
+        unsafe { htslib::bam_mplp_destroy(self.itr) };
+    }
+}
+
+
+/// Iterator over the allele counts of pileups.
+pub struct Alleles<'a> {
+    pileups: Pileups<'a>,
+    min_baseq: u8,
+}
+
+
+impl<'a> Iterator for Alleles<'a> {
+    type Item = Result<AlleleCounts, PileupError>;
+
+    fn next(&mut self) -> Option<Result<AlleleCounts, PileupError>> {
+        let min_baseq = self.min_baseq;
+        self.pileups.next().map(|pileup| pileup.map(|pileup| pileup.allele_counts(min_baseq)))
+    }
+}
+
+
+/// Pileups over the same position of several readers.
+pub struct MultiPileup<'a> {
+    tid: u32,
+    pos: u32,
+    pileups: Vec<Pileup<'a>>,
+}
+
+
+impl<'a> MultiPileup<'a> {
+    pub fn tid(&self) -> u32 {
+        self.tid
+    }
+
+    pub fn pos(&self) -> u32 {
+        self.pos
+    }
+
+    /// The pileups of the readers, in the order the readers were given.
+    /// Readers without reads at this position have a pileup of depth zero.
+    pub fn pileups(&self) -> &[Pileup<'a>] {
+        &self.pileups
+    }
+}
+
+
+/// Pileups of several readers (e.g. tumor and normal), aligned by position.
+/// Like `Pileups`, these are obtained with `next` and borrow the iterator.
+pub struct MultiPileups<'a> {
+    itr: htslib::bam_mplp_t,
+    _sources: Vec<Box<Source<'a>>>,
+    min_baseq: u8,
+    depths: Vec<i32>,
+    inners: Vec<*const htslib::bam_pileup1_t>,
+}
+
+
+impl<'a> MultiPileups<'a> {
+    /// Create new pileups over the given readers, which may be of different types
+    /// (e.g. a `Reader` and an `IndexedReader`). All readers have to be sorted by
+    /// coordinate and share the same sequence dictionary.
+    pub fn new(readers: &[&'a bam::Read]) -> Self {
+        Self::with_filter(readers, None)
+    }
+
+    fn with_filter(readers: &[&'a bam::Read], filter: Option<&'a Filter<'a>>) -> Self {
+        let mut sources: Vec<_> = readers.iter()
+                                         .map(|&reader| Box::new(Source { reader: reader, filter: filter }))
+                                         .collect();
+        let mut data: Vec<*mut ::libc::c_void> = sources.iter_mut()
+                                                         .map(|source| &mut **source as *mut Source as *mut ::libc::c_void)
+                                                         .collect();
+        let itr = unsafe {
+            htslib::bam_mplp_init(readers.len() as i32, Some(read_source), data.as_mut_ptr())
         };
+        MultiPileups {
+            itr: itr,
+            _sources: sources,
+            min_baseq: 0,
+            depths: vec![0; readers.len()],
+            inners: vec![ptr::null(); readers.len()],
+        }
+    }
 
-        match inner.is_null() {
-            true if depth == -1 => Some(Err(PileupError::Some)),
-            true              => None,
-            false             => Some(Ok(
-                    Pileup {
-                        inner: inner,
-                        depth: depth as u32,
-                        tid: tid as u32,
-                        pos: pos as u32,
-                    }
-            ))
+    pub fn set_max_depth(&mut self, depth: u32) {
+        unsafe { htslib::bam_mplp_set_maxcnt(self.itr, depth as i32); }
+    }
+
+    /// The pileups of the next position, or None if all records have been consumed.
+    /// The pileups borrow the iterator, i.e. they have to be dropped before advancing.
+    pub fn next<'p>(&'p mut self) -> Option<Result<MultiPileup<'p>, PileupError>> {
+        let (mut tid, mut pos) = (0i32, 0i32);
+        let ret = unsafe {
+            htslib::bam_mplp_auto(self.itr, &mut tid, &mut pos, self.depths.as_mut_ptr(), self.inners.as_mut_ptr())
+        };
+
+        match ret {
+            0 => None,
+            ret if ret < 0 || self.depths.iter().any(|&depth| depth < 0) => Some(Err(PileupError::Some)),
+            _ => Some(Ok(MultiPileup {
+                tid: tid as u32,
+                pos: pos as u32,
+                pileups: self.inners.iter().zip(self.depths.iter()).map(|(&inner, &depth)| {
+                    let column = Column { inner: inner, depth: depth as u32, tid: tid as u32, pos: pos as u32 };
+                    Pileup::new(column, self.min_baseq)
+                }).collect()
+            }))
         }
     }
 }
 
 
-impl Drop for Pileups {
+impl<'a> Drop for MultiPileups<'a> {
     fn drop(&mut self) {
-        unsafe {
-            htslib::bam_plp_reset(self.itr);
-            htslib::bam_plp_destroy(self.itr);
+        unsafe { htslib::bam_mplp_destroy(self.itr); }
+    }
+}
+
+
+/// Builder for pileups that skip records and bases according to configurable criteria,
+/// analogous to the options of `samtools mpileup`.
+///
+/// ```no_run
+/// use rust_htslib::bam;
+/// use rust_htslib::bam::pileup::PileupBuilder;
+///
+/// let bam = bam::Reader::from_path(&"test/test.bam").unwrap();
+/// let mut builder = PileupBuilder::new();
+/// builder.min_mapq(20).min_baseq(13).overlaps(true).filter(|record| record.insert_size().abs() < 1000);
+/// let mut pileups = builder.pileup(&bam);
+/// while let Some(pileup) = pileups.next() {
+///     let pileup = pileup.unwrap();
+///     println!("{}:{} depth {}", pileup.tid(), pileup.pos(), pileup.depth());
+/// }
+/// ```
+pub struct PileupBuilder<'f> {
+    filter: Filter<'f>,
+    min_baseq: u8,
+    overlaps: bool,
+    max_depth: Option<u32>,
+    zero_depth: bool,
+}
+
+
+impl<'f> PileupBuilder<'f> {
+    /// Create a new builder. By default, unmapped, secondary, QC-failed and duplicate
+    /// records are skipped (as in `samtools mpileup`).
+    pub fn new() -> Self {
+        PileupBuilder {
+            filter: Filter::new(),
+            min_baseq: 0,
+            overlaps: false,
+            max_depth: None,
+            zero_depth: false,
+        }
+    }
+
+    /// Only use records for which the given closure returns true.
+    pub fn filter<F: Fn(&record::Record) -> bool + 'f>(&mut self, filter: F) -> &mut Self {
+        self.filter.closure = Some(Box::new(filter));
+        self
+    }
+
+    /// Only use records that have all of the given flags set.
+    pub fn required_flags(&mut self, flags: u16) -> &mut Self {
+        self.filter.required_flags = flags;
+        self
+    }
+
+    /// Skip records that have any of the given flags set.
+    pub fn excluded_flags(&mut self, flags: u16) -> &mut Self {
+        self.filter.excluded_flags = flags;
+        self
+    }
+
+    /// Skip records with a mapping quality below the given value.
+    pub fn min_mapq(&mut self, mapq: u8) -> &mut Self {
+        self.filter.min_mapq = mapq;
+        self
+    }
+
+    /// Skip bases with a base quality below the given value.
+    pub fn min_baseq(&mut self, baseq: u8) -> &mut Self {
+        self.min_baseq = baseq;
+        self
+    }
+
+    /// Correct base qualities where the reads of a pair overlap, such that the bases are
+    /// not counted twice (as `bam_mplp_init_overlaps`). In combination with `min_baseq`,
+    /// only one base of each overlapping pair is kept.
+    pub fn overlaps(&mut self, correct: bool) -> &mut Self {
+        self.overlaps = correct;
+        self
+    }
+
+    /// Set the maximum number of reads per position.
+    pub fn max_depth(&mut self, depth: u32) -> &mut Self {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    /// Also yield positions without reads (with depth zero) in region pileups,
+    /// such that every position of the region is covered.
+    pub fn zero_depth(&mut self, zero_depth: bool) -> &mut Self {
+        self.zero_depth = zero_depth;
+        self
+    }
+
+    /// Pileups of the given reader.
+    pub fn pileup<'a, R: bam::Read>(&'a self, reader: &'a R) -> Pileups<'a> {
+        let mut pileups = Pileups::with_filter(reader, Some(&self.filter));
+        pileups.min_baseq = self.min_baseq;
+        self.setup(pileups.itr);
+        pileups
+    }
+
+    /// Pileups of the given region (0-based, half-open) of an indexed
+    /// reader. Only positions within the region are yielded, with zero-depth positions
+    /// included if configured via `zero_depth`.
+    pub fn region_pileup<'a>(
+        &'a self,
+        reader: &'a mut bam::IndexedReader,
+        tid: u32,
+        beg: u32,
+        end: u32
+    ) -> Result<Pileups<'a>, bam::SeekError> {
+        try!(reader.seek(tid, beg, end));
+        let mut pileups = self.pileup(reader);
+        pileups.set_region(tid, beg, end, self.zero_depth);
+        Ok(pileups)
+    }
+
+    /// Pileups of several readers, aligned by position.
+    pub fn multi_pileup<'a>(&'a self, readers: &[&'a bam::Read]) -> MultiPileups<'a> {
+        let mut pileups = MultiPileups::with_filter(readers, Some(&self.filter));
+        pileups.min_baseq = self.min_baseq;
+        self.setup(pileups.itr);
+        pileups
+    }
+
+    fn setup(&self, itr: htslib::bam_mplp_t) {
+        if self.overlaps {
+            unsafe { htslib::bam_mplp_init_overlaps(itr) };
+        }
+        if let Some(depth) = self.max_depth {
+            unsafe { htslib::bam_mplp_set_maxcnt(itr, depth as i32) };
         }
     }
 }
 
 
+/// Unmapped, secondary, QC-failed and duplicate.
+pub(crate) const DEFAULT_EXCLUDED_FLAGS: u16 = 0x4 | 0x100 | 0x200 | 0x400;
+
+
+/// Criteria for records to be used in a pileup or depth computation.
+pub(crate) struct Filter<'f> {
+    pub(crate) closure: Option<Box<Fn(&record::Record) -> bool + 'f>>,
+    pub(crate) required_flags: u16,
+    pub(crate) excluded_flags: u16,
+    pub(crate) min_mapq: u8,
+}
+
+
+impl<'f> Filter<'f> {
+    /// Filter that skips records with any of the default excluded flags.
+    pub(crate) fn new() -> Self {
+        Filter {
+            closure: None,
+            required_flags: 0,
+            excluded_flags: DEFAULT_EXCLUDED_FLAGS,
+            min_mapq: 0,
+        }
+    }
+
+    pub(crate) fn passes(&self, record: &record::Record) -> bool {
+        let flags = record.flags();
+        flags & self.required_flags == self.required_flags &&
+        flags & self.excluded_flags == 0 &&
+        record.mapq() >= self.min_mapq &&
+        self.closure.as_ref().map_or(true, |closure| closure(record))
+    }
+}
+
+
+/// A reader with an optional filter, passed to htslib pileup callbacks.
+struct Source<'a> {
+    reader: &'a bam::Read,
+    filter: Option<&'a Filter<'a>>,
+}
+
+
+/// Callback for htslib pileups, reading the next record that passes the filter.
+extern fn read_source(data: *mut ::libc::c_void, record: *mut htslib::bam1_t) -> ::libc::c_int {
+    let source = unsafe { &*(data as *const Source) };
+    let mut record = record::Record::from_inner(record);
+    loop {
+        match source.reader.read(&mut record) {
+            Ok(()) => if source.filter.map_or(true, |filter| filter.passes(&record)) {
+                return 0;
+            },
+            Err(bam::ReadError::NoMoreRecord) => return -1,
+            Err(_)                            => return -2
+        }
+    }
+}
+
+
+/// Whether a base passes the minimum base quality. Deletions and reference skips always pass.
+fn passes_baseq(plp: &htslib::bam_pileup1_t, min_baseq: u8) -> bool {
+    if min_baseq == 0 || plp.isdel_ishead_istail_isrefskip_isaux & (IS_DEL | IS_REFSKIP) != 0 {
+        return true;
+    }
+    // records without sequence have no base qualities to check
+    record::Record::from_inner(plp.b).qual().get(plp.qpos as usize).map_or(true, |&qual| qual >= min_baseq)
+}
+
+
+const IS_DEL: u32 = 0x1;
+const IS_HEAD: u32 = 0x2;
+const IS_TAIL: u32 = 0x4;
+const IS_REFSKIP: u32 = 0x8;
+
+
 quick_error! {
     #[derive(Debug)]
     pub enum PileupError {
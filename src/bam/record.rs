@@ -4,9 +4,12 @@
 // except according to those terms.
 
 
+use std::borrow::Cow;
 use std::slice;
 use std::ffi;
 use std::ops;
+use std::fmt;
+use std::str::FromStr;
 
 use itertools::Itertools;
 
@@ -22,7 +25,7 @@ macro_rules! flag {
         }
 
         pub fn $set(&mut self) {
-            self.inner_mut().core.flag |= $bit;
+            self.core_mut().flag |= $bit;
         }
     )
 }
@@ -30,8 +33,9 @@ macro_rules! flag {
 
 /// A BAM record.
 pub struct Record {
-    pub inner: *mut htslib::bam1_t,
+    pub(crate) inner: *mut htslib::bam1_t,
     own: bool,
+    cigar: Option<CigarString>,
 }
 
 
@@ -43,24 +47,33 @@ impl Record {
     /// Create an empty BAM record.
     pub fn new() -> Self {
         let inner = unsafe { htslib::bam_init1() };
-        let mut record = Record { inner: inner, own: true };
+        let mut record = Record { inner: inner, own: true, cigar: None };
         record.inner_mut().m_data = 0;
         record
     }
 
     pub fn from_inner(inner: *mut htslib::bam1_t) -> Self {
-        Record { inner: inner, own: false }
+        Record { inner: inner, own: false, cigar: None }
     }
 
     fn data(&self) -> &[u8] {
         unsafe { slice::from_raw_parts(self.inner().data, self.inner().l_data as usize) }
     }
 
+    /// Mutable access to the underlying htslib record. This clears the cached CIGAR string,
+    /// since the data block may be modified through the returned reference.
     #[inline]
     pub fn inner_mut(&mut self) -> &mut htslib::bam1_t {
+        self.cigar = None;
         unsafe { &mut *self.inner }
     }
 
+    /// Mutable access to the fixed-length fields, which leaves the cached CIGAR string intact.
+    #[inline]
+    fn core_mut(&mut self) -> &mut htslib::bam1_core_t {
+        unsafe { &mut (*self.inner).core }
+    }
+
     #[inline]
     pub fn inner(&self) -> &htslib::bam1_t {
         unsafe { &*self.inner }
@@ -73,7 +86,7 @@ impl Record {
 
     /// Set target id.
     pub fn set_tid(&mut self, tid: i32) {
-        self.inner_mut().core.tid = tid;
+        self.core_mut().tid = tid;
     }
 
     /// Get position (0-based).
@@ -83,19 +96,17 @@ impl Record {
 
     /// Set position (0-based).
     pub fn set_pos(&mut self, pos: i32) {
-        self.inner_mut().core.pos = pos;
+        self.core_mut().pos = pos;
     }
 
-    pub fn end_pos(&self, cigar: &[Cigar]) -> i32 {
-        let mut pos = self.pos();
-        for c in cigar {
-            match c {
-                &Cigar::Match(l) | &Cigar::RefSkip(l) | &Cigar::Equal(l) | &Cigar::Diff(l) => pos += l as i32,
-                &Cigar::Back(l) => pos -= l as i32,
-                _ => ()
-            }
-        }
-        pos
+    /// Get end position (0-based, exclusive), i.e. the position plus the reference length
+    /// of the CIGAR string. Uses the cached CIGAR string if present.
+    pub fn end_pos(&self) -> i32 {
+        let ref_len = match self.cigar {
+            Some(ref cigar) => cigar.ref_len(),
+            None            => self.raw_cigar().iter().map(|&c| Cigar::decode(c).ref_len()).sum()
+        };
+        self.pos() + ref_len as i32
     }
 
     pub fn bin(&self) -> u16 {
@@ -103,7 +114,7 @@ impl Record {
     }
 
     pub fn set_bin(&mut self, bin: u16) {
-        self.inner_mut().core.bin = bin;
+        self.core_mut().bin = bin;
     }
 
     /// Get MAPQ.
@@ -113,7 +124,7 @@ impl Record {
 
     /// Set MAPQ.
     pub fn set_mapq(&mut self, mapq: u8) {
-        self.inner_mut().core.qual = mapq;
+        self.core_mut().qual = mapq;
     }
 
     /// Get raw flags.
@@ -123,12 +134,12 @@ impl Record {
 
     /// Set raw flags.
     pub fn set_flags(&mut self, flags: u16) {
-        self.inner_mut().core.flag = flags;
+        self.core_mut().flag = flags;
     }
 
     /// Unset all flags.
     pub fn unset_flags(&mut self) {
-        self.inner_mut().core.flag = 0;
+        self.core_mut().flag = 0;
     }
 
     /// Get target id of mate.
@@ -138,7 +149,7 @@ impl Record {
 
     /// Set target id of mate.
     pub fn set_mtid(&mut self, mtid: i32) {
-        self.inner_mut().core.mtid = mtid;
+        self.core_mut().mtid = mtid;
     }
 
     /// Get mate position.
@@ -148,7 +159,7 @@ impl Record {
 
     /// Set mate position.
     pub fn set_mpos(&mut self, mpos: i32) {
-        self.inner_mut().core.mpos = mpos;
+        self.core_mut().mpos = mpos;
     }
 
     /// Get insert size.
@@ -158,7 +169,7 @@ impl Record {
 
     /// Set insert size.
     pub fn set_insert_size(&mut self, insert_size: i32) {
-        self.inner_mut().core.isize = insert_size;
+        self.core_mut().isize = insert_size;
     }
 
     fn qname_len(&self) -> usize {
@@ -170,7 +181,7 @@ impl Record {
         &self.data()[..self.qname_len()-1] // -1 ignores the termination symbol
     }
 
-    /// Set variable length data (qname, cigar, seq, qual).
+    /// Set variable length data (qname, cigar, seq, qual). This clears the cached CIGAR string.
     pub fn set(&mut self, qname: &[u8], cigar: &[Cigar], seq: &[u8], qual: &[u8]) {
         self.inner_mut().l_data = (qname.len() + 1 + cigar.len() * 4 + ((seq.len() as f32 / 2.0).ceil() as usize) + qual.len()) as i32;
         
@@ -225,25 +236,117 @@ impl Record {
         unsafe { slice::from_raw_parts(self.data()[self.qname_len()..].as_ptr() as *const u32, self.cigar_len()) }
     }
 
-    /// Get cigar sequence.
-    pub fn cigar(&self) -> Vec<Cigar> {
-        let raw = self.raw_cigar();
-        raw.iter().map(|&c| {
-            let len = c >> 4;
-            match c & 0b1111 {
-                0 => Cigar::Match(len),
-                1 => Cigar::Ins(len),
-                2 => Cigar::Del(len),
-                3 => Cigar::RefSkip(len),
-                4 => Cigar::SoftClip(len),
-                5 => Cigar::HardClip(len),
-                6 => Cigar::Pad(len),
-                7 => Cigar::Equal(len),
-                8 => Cigar::Diff(len),
-                9 => Cigar::Back(len),
-                _ => panic!("Unexpected cigar type"),
+    /// Get cigar sequence. Borrows the cached CIGAR string if present, and decodes it otherwise.
+    pub fn cigar(&self) -> Cow<CigarString> {
+        match self.cigar {
+            Some(ref cigar) => Cow::Borrowed(cigar),
+            None            => Cow::Owned(CigarString(self.raw_cigar().iter().map(|&c| Cigar::decode(c)).collect()))
+        }
+    }
+
+    /// Decode the CIGAR string and keep it with the record, such that subsequent calls to
+    /// `cigar`, `cigar_cached` and `end_pos` do not have to decode it again.
+    /// The cache is cleared when reading a new record into this one, and by `set`, `push_aux`
+    /// and `inner_mut`.
+    pub fn cache_cigar(&mut self) {
+        self.cigar = None;
+        self.cigar = Some(self.cigar().into_owned());
+    }
+
+    /// Get the cached cigar sequence, or None if `cache_cigar` has not been called.
+    pub fn cigar_cached(&self) -> Option<&CigarString> {
+        self.cigar.as_ref()
+    }
+
+    /// Clear the cached CIGAR string, which is necessary whenever the record data is
+    /// replaced by htslib.
+    pub(crate) fn clear_cigar_cache(&mut self) {
+        self.cigar = None;
+    }
+
+    /// Iterator over the aligned pairs of query position (offset in the stored sequence,
+    /// i.e. including soft clips) and reference position (0-based). By default, only
+    /// matched positions are returned. Insertions, soft clips, deletions and introns can be
+    /// included via the options of `AlignedPairs`, with a position of None on the side
+    /// they do not consume.
+    pub fn aligned_pairs(&self) -> AlignedPairs {
+        let cigar = if self.is_unmapped() || self.pos() < 0 { CigarString(Vec::new()) } else { self.cigar().into_owned() };
+        AlignedPairs {
+            cigar: cigar,
+            op: 0,
+            offset: 0,
+            qpos: 0,
+            rpos: self.pos() as u32,
+            insertions: false,
+            soft_clips: false,
+            deletions: false,
+            introns: false,
+        }
+    }
+
+    /// Reference intervals (0-based, half-open) of the gapless blocks of the alignment,
+    /// i.e. matches separated by deletions, introns or insertions.
+    pub fn aligned_blocks(&self) -> Vec<(u32, u32)> {
+        let mut blocks = Vec::new();
+        self.walk_cigar(|c, _, rpos| {
+            match *c {
+                Cigar::Match(len) | Cigar::Equal(len) | Cigar::Diff(len) => blocks.push((rpos, rpos + len)),
+                _ => ()
+            }
+            false
+        });
+        blocks
+    }
+
+    /// Reference position aligned to the given query position, or None if the query
+    /// position is inserted, soft clipped or outside of the read.
+    pub fn ref_pos_at(&self, query_pos: u32) -> Option<u32> {
+        let mut ref_pos = None;
+        self.walk_cigar(|c, qpos, rpos| {
+            let len = c.query_len();
+            if query_pos >= qpos && query_pos < qpos + len {
+                if c.ref_len() > 0 {
+                    ref_pos = Some(rpos + query_pos - qpos);
+                }
+                return true;
+            }
+            false
+        });
+        ref_pos
+    }
+
+    /// Query position aligned to the given reference position, or None if the reference
+    /// position is deleted, skipped (intron) or not covered by the alignment.
+    pub fn query_pos_at(&self, ref_pos: u32) -> Option<u32> {
+        let mut query_pos = None;
+        self.walk_cigar(|c, qpos, rpos| {
+            let len = c.ref_len();
+            if ref_pos >= rpos && ref_pos < rpos + len {
+                if c.query_len() > 0 {
+                    query_pos = Some(qpos + ref_pos - rpos);
+                }
+                return true;
+            }
+            false
+        });
+        query_pos
+    }
+
+    /// Call the given closure with each CIGAR operation and the query and reference
+    /// position it starts at, until it returns true. Unmapped records have no operations.
+    fn walk_cigar<F: FnMut(&Cigar, u32, u32) -> bool>(&self, mut f: F) {
+        if self.is_unmapped() || self.pos() < 0 {
+            return;
+        }
+        let cigar = self.cigar();
+        let (mut qpos, mut rpos) = (0, self.pos() as u32);
+        for c in cigar.iter() {
+            if f(c, qpos, rpos) {
+                return;
             }
-        }).collect()
+            qpos += c.query_len();
+            rpos += c.ref_len();
+        }
     }
 
     fn seq_len(&self) -> usize {
@@ -334,6 +437,17 @@ impl Record {
 }
 
 
+impl Clone for Record {
+    /// Create an owned deep copy of the record.
+    fn clone(&self) -> Self {
+        let mut copy = Record::new();
+        unsafe { htslib::bam_copy1(copy.inner, self.inner) };
+        copy.cigar = self.cigar.clone();
+        copy
+    }
+}
+
+
 impl Drop for Record {
     fn drop(&mut self) {
         if self.own {
@@ -446,7 +560,7 @@ unsafe impl<'a> Send for Seq<'a> {}
 unsafe impl<'a> Sync for Seq<'a> {}
 
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub enum Cigar {
     Match(u32),  // M
     Ins(u32),  // I
@@ -462,6 +576,23 @@ pub enum Cigar {
 
 
 impl Cigar {
+    fn decode(raw: u32) -> Self {
+        let len = raw >> 4;
+        match raw & 0b1111 {
+            0 => Cigar::Match(len),
+            1 => Cigar::Ins(len),
+            2 => Cigar::Del(len),
+            3 => Cigar::RefSkip(len),
+            4 => Cigar::SoftClip(len),
+            5 => Cigar::HardClip(len),
+            6 => Cigar::Pad(len),
+            7 => Cigar::Equal(len),
+            8 => Cigar::Diff(len),
+            9 => Cigar::Back(len),
+            _ => panic!("Unexpected cigar type"),
+        }
+    }
+
     fn encode(&self) -> u32 {
         match *self {
             Cigar::Match(len)    => len << 4 | 0,
@@ -479,5 +610,288 @@ impl Cigar {
 }
 
 
+impl fmt::Display for Cigar {
+    /// Format the operation as in SAM, e.g. `27M`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (len, op) = match *self {
+            Cigar::Match(len)    => (len, 'M'),
+            Cigar::Ins(len)      => (len, 'I'),
+            Cigar::Del(len)      => (len, 'D'),
+            Cigar::RefSkip(len)  => (len, 'N'),
+            Cigar::SoftClip(len) => (len, 'S'),
+            Cigar::HardClip(len) => (len, 'H'),
+            Cigar::Pad(len)      => (len, 'P'),
+            Cigar::Equal(len)    => (len, '='),
+            Cigar::Diff(len)     => (len, 'X'),
+            Cigar::Back(len)     => (len, 'B'),
+        };
+        write!(f, "{}{}", len, op)
+    }
+}
+
+
+impl Cigar {
+    /// Number of query bases consumed by the operation.
+    pub fn query_len(&self) -> u32 {
+        match *self {
+            Cigar::Match(len) | Cigar::Ins(len) | Cigar::SoftClip(len) | Cigar::Equal(len) |
+            Cigar::Diff(len) => len,
+            _ => 0
+        }
+    }
+
+    /// Number of reference bases consumed by the operation.
+    pub fn ref_len(&self) -> u32 {
+        match *self {
+            Cigar::Match(len) | Cigar::Del(len) | Cigar::RefSkip(len) | Cigar::Equal(len) |
+            Cigar::Diff(len) => len,
+            _ => 0
+        }
+    }
+}
+
+
 unsafe impl Send for Cigar {}
 unsafe impl Sync for Cigar {}
+
+
+/// Iterator over the aligned pairs of query and reference positions of a record, see
+/// `Record::aligned_pairs`.
+pub struct AlignedPairs {
+    cigar: CigarString,
+    op: usize,
+    offset: u32,
+    qpos: u32,
+    rpos: u32,
+    insertions: bool,
+    soft_clips: bool,
+    deletions: bool,
+    introns: bool,
+}
+
+
+impl AlignedPairs {
+    /// Include inserted query positions, with a reference position of None.
+    pub fn insertions(mut self, include: bool) -> Self {
+        self.insertions = include;
+        self
+    }
+
+    /// Include soft clipped query positions, with a reference position of None.
+    pub fn soft_clips(mut self, include: bool) -> Self {
+        self.soft_clips = include;
+        self
+    }
+
+    /// Include deleted reference positions, with a query position of None.
+    pub fn deletions(mut self, include: bool) -> Self {
+        self.deletions = include;
+        self
+    }
+
+    /// Include skipped reference positions (introns), with a query position of None.
+    pub fn introns(mut self, include: bool) -> Self {
+        self.introns = include;
+        self
+    }
+}
+
+
+impl Iterator for AlignedPairs {
+    type Item = (Option<u32>, Option<u32>);
+
+    fn next(&mut self) -> Option<(Option<u32>, Option<u32>)> {
+        loop {
+            let c = match self.cigar.get(self.op) {
+                Some(&c) => c,
+                None     => return None
+            };
+            let (query_len, ref_len) = (c.query_len(), c.ref_len());
+            let include = match c {
+                Cigar::Match(_) | Cigar::Equal(_) | Cigar::Diff(_) => true,
+                Cigar::Ins(_)      => self.insertions,
+                Cigar::SoftClip(_) => self.soft_clips,
+                Cigar::Del(_)      => self.deletions,
+                Cigar::RefSkip(_)  => self.introns,
+                _                  => false
+            };
+            if !include || self.offset >= query_len.max(ref_len) {
+                if self.offset == 0 {
+                    self.qpos += query_len;
+                    self.rpos += ref_len;
+                }
+                self.op += 1;
+                self.offset = 0;
+                continue;
+            }
+
+            let pair = (
+                if query_len > 0 { Some(self.qpos) } else { None },
+                if ref_len > 0 { Some(self.rpos) } else { None }
+            );
+            if query_len > 0 {
+                self.qpos += 1;
+            }
+            if ref_len > 0 {
+                self.rpos += 1;
+            }
+            self.offset += 1;
+            return Some(pair);
+        }
+    }
+}
+
+
+/// A CIGAR string, i.e. the operations of an alignment. It dereferences to a slice of
+/// operations, and can be parsed from and formatted to text as in SAM (e.g. `10M2I88M`,
+/// `*` for an empty CIGAR string).
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct CigarString(pub Vec<Cigar>);
+
+
+impl CigarString {
+    /// Length of the query sequence, including soft clips (as `bam_cigar2qlen`).
+    pub fn query_len(&self) -> u32 {
+        self.0.iter().map(|c| c.query_len()).sum()
+    }
+
+    /// Length of the aligned reference sequence (as `bam_cigar2rlen`).
+    pub fn ref_len(&self) -> u32 {
+        self.0.iter().map(|c| c.ref_len()).sum()
+    }
+
+    /// Length of the soft clip at the start of the alignment.
+    pub fn leading_softclips(&self) -> u32 {
+        match self.0.iter().find(|c| !is_hardclip(c)) {
+            Some(&Cigar::SoftClip(len)) => len,
+            _                           => 0
+        }
+    }
+
+    /// Length of the soft clip at the end of the alignment.
+    pub fn trailing_softclips(&self) -> u32 {
+        match self.0.iter().rev().find(|c| !is_hardclip(c)) {
+            Some(&Cigar::SoftClip(len)) => len,
+            _                           => 0
+        }
+    }
+
+    /// Length of the hard clip at the start of the alignment.
+    pub fn leading_hardclips(&self) -> u32 {
+        match self.0.first() {
+            Some(&Cigar::HardClip(len)) => len,
+            _                           => 0
+        }
+    }
+
+    /// Length of the hard clip at the end of the alignment.
+    pub fn trailing_hardclips(&self) -> u32 {
+        match self.0.last() {
+            Some(&Cigar::HardClip(len)) => len,
+            _                           => 0
+        }
+    }
+}
+
+
+fn is_hardclip(c: &Cigar) -> bool {
+    match *c {
+        Cigar::HardClip(_) => true,
+        _                  => false
+    }
+}
+
+
+impl ops::Deref for CigarString {
+    type Target = [Cigar];
+
+    fn deref(&self) -> &[Cigar] {
+        &self.0
+    }
+}
+
+
+impl<'a> IntoIterator for &'a CigarString {
+    type Item = &'a Cigar;
+    type IntoIter = slice::Iter<'a, Cigar>;
+
+    fn into_iter(self) -> slice::Iter<'a, Cigar> {
+        self.0.iter()
+    }
+}
+
+
+impl fmt::Display for CigarString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "*");
+        }
+        for c in &self.0 {
+            try!(write!(f, "{}", c));
+        }
+        Ok(())
+    }
+}
+
+
+impl FromStr for CigarString {
+    type Err = CigarError;
+
+    fn from_str(s: &str) -> Result<Self, CigarError> {
+        if s == "*" {
+            return Ok(CigarString(Vec::new()));
+        }
+        let mut cigar = Vec::new();
+        let mut len: Option<u32> = None;
+        for c in s.chars() {
+            if let Some(digit) = c.to_digit(10) {
+                len = match len.unwrap_or(0).checked_mul(10).and_then(|len| len.checked_add(digit)) {
+                    Some(len) if len < 1 << 28 => Some(len),
+                    _                          => return Err(CigarError::InvalidLength)
+                };
+                continue;
+            }
+            let len = match len.take() {
+                Some(len) => len,
+                None      => return Err(CigarError::MissingLength)
+            };
+            cigar.push(match c {
+                'M' => Cigar::Match(len),
+                'I' => Cigar::Ins(len),
+                'D' => Cigar::Del(len),
+                'N' => Cigar::RefSkip(len),
+                'S' => Cigar::SoftClip(len),
+                'H' => Cigar::HardClip(len),
+                'P' => Cigar::Pad(len),
+                '=' => Cigar::Equal(len),
+                'X' => Cigar::Diff(len),
+                'B' => Cigar::Back(len),
+                _   => return Err(CigarError::UnexpectedOperation(c))
+            });
+        }
+        if len.is_some() || cigar.is_empty() {
+            return Err(CigarError::MissingOperation);
+        }
+        Ok(CigarString(cigar))
+    }
+}
+
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum CigarError {
+        UnexpectedOperation(op: char) {
+            description("unexpected CIGAR operation")
+            display("unexpected CIGAR operation {}", op)
+        }
+        MissingLength {
+            description("missing length of CIGAR operation")
+        }
+        MissingOperation {
+            description("missing CIGAR operation")
+        }
+        InvalidLength {
+            description("CIGAR operation length too large")
+        }
+    }
+}
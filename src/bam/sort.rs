@@ -0,0 +1,376 @@
+// Copyright 2017 Johannes Köster.
+// Licensed under the MIT license (http://opensource.org/licenses/MIT)
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Module for sorting BAM records by coordinate or query name with bounded memory.
+//!
+//! Records are collected in memory until the given limit is reached. Then, they are sorted
+//! and spilled into a temporary BAM file, in a background thread if more than one thread is
+//! used. Finally, all temporary files are merged into the output.
+//!
+//! ```no_run
+//! use rust_htslib::bam;
+//! use rust_htslib::bam::sort::{Sorter, SortOrder};
+//!
+//! let bam = bam::Reader::from_path(&"test/test.bam").unwrap();
+//! Sorter::new(SortOrder::Coordinate)
+//!     .max_mem(100_000_000)
+//!     .threads(4)
+//!     .sort(&bam, &"test/sorted.bam")
+//!     .unwrap();
+//! ```
+
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use tempdir::TempDir;
+
+use htslib;
+use bam;
+use bam::{Read, Record, Header, HeaderView};
+use bam::merge::{Merger, MergeError};
+
+
+/// Maximum number of files that are merged at once.
+const MAX_MERGE_WIDTH: usize = 64;
+
+
+/// Sort order of BAM records.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortOrder {
+    /// By target id (unmapped reads last), position and strand, as `samtools sort`.
+    Coordinate,
+    /// By query name, with numbers compared by value (as `samtools sort -n`), and
+    /// first in template before last in template.
+    QueryName,
+}
+
+
+impl SortOrder {
+    /// Compare two records according to this sort order.
+    pub fn compare(&self, a: &Record, b: &Record) -> Ordering {
+        match *self {
+            SortOrder::Coordinate => {
+                // unmapped reads (tid -1) go last
+                (a.tid() as u32, a.pos(), a.is_reverse()).cmp(&(b.tid() as u32, b.pos(), b.is_reverse()))
+            },
+            SortOrder::QueryName => {
+                match strnum_cmp(a.qname(), b.qname()) {
+                    Ordering::Equal => (a.flags() & 0xc0).cmp(&(b.flags() & 0xc0)),
+                    ord => ord
+                }
+            }
+        }
+    }
+
+    /// Value of the `SO` tag of the `@HD` header line.
+    fn tag(&self) -> &'static [u8] {
+        match *self {
+            SortOrder::Coordinate => b"coordinate",
+            SortOrder::QueryName  => b"queryname",
+        }
+    }
+}
+
+
+/// A configurable sorter for BAM records.
+pub struct Sorter {
+    order: SortOrder,
+    max_mem: usize,
+    threads: usize,
+    tmp_dir: Option<PathBuf>,
+}
+
+
+impl Sorter {
+    /// Create a new sorter with 768MB of memory per thread and a single thread.
+    pub fn new(order: SortOrder) -> Self {
+        Sorter { order: order, max_mem: 768 * 1024 * 1024, threads: 1, tmp_dir: None }
+    }
+
+    /// Set the maximum memory (in bytes) used per thread for records held in memory.
+    /// At most one buffer of this size is held per thread: one is filled with records while
+    /// the others are spilled in the background, i.e. the total is bounded by
+    /// `threads * max_mem`.
+    pub fn max_mem(&mut self, bytes: usize) -> &mut Self {
+        self.max_mem = bytes;
+        self
+    }
+
+    /// Set the number of threads used for sorting, merging and compression.
+    pub fn threads(&mut self, threads: usize) -> &mut Self {
+        self.threads = if threads > 0 { threads } else { 1 };
+        self
+    }
+
+    /// Set the directory for temporary files. By default, the system temp directory is used.
+    pub fn tmp_dir<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.tmp_dir = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// Create the header for the sorted output from a template,
+    /// setting the `SO` tag of the `@HD` line.
+    pub fn header(&self, template: &HeaderView) -> Header {
+        sorted_header(template.as_bytes(), self.order)
+    }
+
+    /// Sort the records of the given reader into a new BAM file.
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - the reader to take the records from
+    /// * `path` - the path of the sorted BAM file
+    pub fn sort<R: Read, P: AsRef<Path>>(&self, reader: &R, path: P) -> Result<(), SortError> {
+        let mut writer = try!(bam::Writer::from_path(path, &self.header(reader.header())));
+        if self.threads > 1 {
+            try!(writer.set_threads(self.threads));
+        }
+        self.sort_into(reader, &mut writer)
+    }
+
+    /// Sort the records of the given reader into the given writer.
+    /// The writer should have been created with a header obtained from `Sorter::header`.
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - the reader to take the records from
+    /// * `writer` - the writer to write the sorted records to
+    pub fn sort_into<R: Read>(&self, reader: &R, writer: &mut bam::Writer) -> Result<(), SortError> {
+        let tmp = try!(match self.tmp_dir {
+            Some(ref dir) => TempDir::new_in(dir, "rust-htslib-sort"),
+            None          => TempDir::new("rust-htslib-sort")
+        });
+        let order = self.order;
+        let mut n_chunks = 0;
+        let mut chunk_path = || {
+            n_chunks += 1;
+            tmp.path().join(format!("chunk{}.bam", n_chunks))
+        };
+
+        let mut chunks = Vec::new();
+        let mut spills = VecDeque::new();
+        let mut buffer = Vec::new();
+        let mut mem = 0;
+        for r in reader.records() {
+            let record = try!(r);
+            mem += record_mem(&record);
+            buffer.push(record);
+            if mem >= self.max_mem {
+                let records = mem::replace(&mut buffer, Vec::new());
+                let path = chunk_path();
+                let header = Header::from_template(reader.header());
+                if self.threads == 1 {
+                    chunks.push(try!(spill(records, order, path, header)));
+                } else {
+                    // wait for the oldest spill, such that at most `threads` buffers are held
+                    if spills.len() == self.threads - 1 {
+                        chunks.push(try!(join(spills.pop_front().unwrap())));
+                    }
+                    spills.push_back(thread::spawn(move || spill(records, order, path, header)));
+                }
+                mem = 0;
+            }
+        }
+
+        if chunks.is_empty() && spills.is_empty() {
+            // everything fits into memory
+            buffer.sort_by(|a, b| order.compare(a, b));
+            for record in &buffer {
+                try!(writer.write(record));
+            }
+            return Ok(());
+        }
+
+        // spill the remaining records while the background spills finish
+        let last = if !buffer.is_empty() {
+            let path = chunk_path();
+            Some(try!(spill(buffer, order, path, Header::from_template(reader.header()))))
+        } else {
+            None
+        };
+        for handle in spills {
+            chunks.push(try!(join(handle)));
+        }
+        chunks.extend(last);
+
+        // merge groups of chunks in parallel until few enough are left for the final merge
+        while chunks.len() > MAX_MERGE_WIDTH {
+            let mut merged = Vec::new();
+            for batch in chunks.chunks(MAX_MERGE_WIDTH * self.threads) {
+                let handles: Vec<_> = batch.chunks(MAX_MERGE_WIDTH).map(|group| {
+                    let group = group.to_vec();
+                    let path = chunk_path();
+                    let header = Header::from_template(reader.header());
+                    thread::spawn(move || -> Result<PathBuf, SortError> {
+                        {
+                            let mut out = try!(bam::Writer::from_path(&path, &header));
+                            try!(merge(&group, order, |record| out.write(record)));
+                        }
+                        Ok(path)
+                    })
+                }).collect();
+                for handle in handles {
+                    merged.push(try!(join(handle)));
+                }
+            }
+            chunks = merged;
+        }
+
+        merge(&chunks, order, |record| writer.write(record))
+    }
+}
+
+
+/// Create a header from SAM header text, setting the `SO` tag of the `@HD` line
+/// (which is added if missing) to the given order.
+pub fn sorted_header(text: &[u8], order: SortOrder) -> Header {
+    let mut lines: Vec<Vec<u8>> = text.split(|&c| c == b'\n')
+                                      .filter(|line| !line.is_empty())
+                                      .map(|line| line.to_owned())
+                                      .collect();
+    let so = [&b"SO:"[..], order.tag()].concat();
+    if lines.first().map_or(false, |line| line.starts_with(b"@HD")) {
+        let hd = lines[0].split(|&c| c == b'\t')
+                         .filter(|field| !field.starts_with(b"SO:"))
+                         .chain(Some(&so[..]))
+                         .collect::<Vec<_>>()
+                         .join(&b'\t');
+        lines[0] = hd;
+    } else {
+        lines.insert(0, [&b"@HD\tVN:1.4\t"[..], &so].concat());
+    }
+    Header::from_bytes(&lines.join(&b'\n'))
+}
+
+
+/// Approximate memory occupied by a record.
+fn record_mem(record: &Record) -> usize {
+    record.inner().m_data as usize + mem::size_of::<htslib::bam1_t>() + mem::size_of::<Record>()
+}
+
+
+/// Sort the given records and write them to a temporary BAM file.
+fn spill(mut records: Vec<Record>, order: SortOrder, path: PathBuf, header: Header) -> Result<PathBuf, SortError> {
+    records.sort_by(|a, b| order.compare(a, b));
+    {
+        let mut writer = try!(bam::Writer::from_path(&path, &header));
+        for record in &records {
+            try!(writer.write(record));
+        }
+    }
+    Ok(path)
+}
+
+
+fn join(handle: thread::JoinHandle<Result<PathBuf, SortError>>) -> Result<PathBuf, SortError> {
+    match handle.join() {
+        Ok(res) => res,
+        Err(_)  => Err(SortError::ThreadPanic)
+    }
+}
+
+
+/// K-way merge of the given sorted BAM files.
+fn merge<F>(paths: &[PathBuf], order: SortOrder, mut write: F) -> Result<(), SortError>
+    where F: FnMut(&Record) -> Result<(), bam::WriteError>
+{
+    let mut readers = Vec::with_capacity(paths.len());
+    for path in paths {
+        readers.push(try!(bam::Reader::from_path(path)));
+    }
+    let mut merger = try!(Merger::new(readers, order));
+    let mut record = Record::new();
+    loop {
+        match merger.read(&mut record) {
+            Ok(()) => try!(write(&record)),
+            Err(bam::ReadError::NoMoreRecord) => return Ok(()),
+            Err(e) => return Err(SortError::ReadError(e))
+        }
+    }
+}
+
+
+/// Compare strings such that embedded numbers are compared by value (as in samtools).
+fn strnum_cmp(a: &[u8], b: &[u8]) -> Ordering {
+    let is_digit = |s: &[u8], i: usize| i < s.len() && (s[i] as char).is_digit(10);
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if is_digit(a, i) && is_digit(b, j) {
+            while i < a.len() && a[i] == b'0' {
+                i += 1;
+            }
+            while j < b.len() && b[j] == b'0' {
+                j += 1;
+            }
+            while is_digit(a, i) && is_digit(b, j) && a[i] == b[j] {
+                i += 1;
+                j += 1;
+            }
+            if is_digit(a, i) && is_digit(b, j) {
+                // the longer number is larger, otherwise the first differing digit decides
+                let mut k = 0;
+                while is_digit(a, i + k) && is_digit(b, j + k) {
+                    k += 1;
+                }
+                return if is_digit(a, i + k) {
+                    Ordering::Greater
+                } else if is_digit(b, j + k) {
+                    Ordering::Less
+                } else {
+                    a[i].cmp(&b[j])
+                };
+            } else if is_digit(a, i) {
+                return Ordering::Greater;
+            } else if is_digit(b, j) {
+                return Ordering::Less;
+            } else if i != j {
+                // equal numbers with a different number of leading zeros
+                return if i < j { Ordering::Greater } else { Ordering::Less };
+            }
+        } else {
+            if a[i] != b[j] {
+                return a[i].cmp(&b[j]);
+            }
+            i += 1;
+            j += 1;
+        }
+    }
+    (a.len() - i).cmp(&(b.len() - j))
+}
+
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum SortError {
+        ReadError(err: bam::ReadError) {
+            from()
+        }
+        WriteError(err: bam::WriteError) {
+            from()
+        }
+        ReaderPathError(err: bam::ReaderPathError) {
+            from()
+        }
+        WriterPathError(err: bam::WriterPathError) {
+            from()
+        }
+        ThreadingError(err: bam::ThreadingError) {
+            from()
+        }
+        MergeError(err: MergeError) {
+            from()
+        }
+        IOError(err: io::Error) {
+            from()
+        }
+        ThreadPanic {
+            description("sorting thread panicked")
+        }
+    }
+}
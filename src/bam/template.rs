@@ -0,0 +1,292 @@
+// Copyright 2017 Johannes Köster.
+// Licensed under the MIT license (http://opensource.org/licenses/MIT)
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Module for grouping records into templates, i.e. all records sharing a query name.
+//!
+//! `Templates` expects input where the records of a template are adjacent (e.g. sorted by
+//! query name or collated). `Collated` pairs records of coordinate-sorted input by
+//! buffering incomplete templates.
+//!
+//! ```no_run
+//! use rust_htslib::bam;
+//! use rust_htslib::bam::Read;
+//! use rust_htslib::bam::template::Templates;
+//!
+//! let bam = bam::Reader::from_path(&"test/test_namesorted.bam").unwrap();
+//! for template in Templates::new(bam.records()) {
+//!     let template = template.unwrap();
+//!     if let (Some(r1), Some(r2)) = (template.first(), template.last()) {
+//!         println!("{} {}", r1.pos(), r2.pos());
+//!     }
+//! }
+//! ```
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use bam;
+use bam::Record;
+use bam::record::Aux;
+
+
+/// All records of a template, in the order they were read.
+pub struct Template {
+    pub records: Vec<Record>,
+}
+
+
+impl Template {
+    fn new(record: Record) -> Self {
+        Template { records: vec![record] }
+    }
+
+    /// Query name shared by all records of the template.
+    pub fn qname(&self) -> &[u8] {
+        self.records[0].qname()
+    }
+
+    /// The primary record of the first segment (R1), or of an unpaired read.
+    pub fn first(&self) -> Option<&Record> {
+        self.primary().find(|rec| rec.is_first_in_template() || !rec.is_last_in_template())
+    }
+
+    /// The primary record of the last segment (R2).
+    pub fn last(&self) -> Option<&Record> {
+        self.primary().find(|rec| rec.is_last_in_template() && !rec.is_first_in_template())
+    }
+
+    /// Secondary records of the template.
+    pub fn secondary(&self) -> Vec<&Record> {
+        self.records.iter().filter(|rec| rec.is_secondary()).collect()
+    }
+
+    /// Supplementary records of the template.
+    pub fn supplementary(&self) -> Vec<&Record> {
+        self.records.iter().filter(|rec| rec.is_supplementary()).collect()
+    }
+
+    /// Whether the template is paired.
+    pub fn is_paired(&self) -> bool {
+        self.records.iter().any(|rec| rec.is_paired())
+    }
+
+    /// Whether all primary records of the template are present, i.e. R1 and R2 for
+    /// paired templates and the single primary record otherwise.
+    pub fn is_complete(&self) -> bool {
+        if self.is_paired() {
+            self.first().is_some() && self.last().is_some()
+        } else {
+            self.primary().next().is_some()
+        }
+    }
+
+    fn primary<'a>(&'a self) -> Box<Iterator<Item=&'a Record> + 'a> {
+        Box::new(self.records.iter().filter(|rec| !rec.is_secondary() && !rec.is_supplementary()))
+    }
+
+    /// Whether all supplementary records announced by the `SA` tags of the primary
+    /// records are present.
+    fn has_supplementary(&self) -> bool {
+        let announced: usize = self.primary().map(|rec| match rec.aux(b"SA") {
+            Some(Aux::String(sa)) => sa.split(|&c| c == b';').filter(|s| !s.is_empty()).count(),
+            _ => 0
+        }).sum();
+        self.supplementary().len() >= announced
+    }
+}
+
+
+/// Iterator over the templates of input where records of the same template are adjacent,
+/// e.g. input sorted by query name or collated.
+///
+/// Returns `TemplateError::Ungrouped` if a template misses one of its primary records,
+/// which happens if the input is not grouped by query name. If templates may be
+/// legitimately incomplete (e.g. because mates have been filtered), use `allow_incomplete`.
+pub struct Templates<I: Iterator<Item=Result<Record, bam::ReadError>>> {
+    records: I,
+    next: Option<Record>,
+    allow_incomplete: bool,
+}
+
+
+impl<I: Iterator<Item=Result<Record, bam::ReadError>>> Templates<I> {
+    /// Create a new iterator over the templates of the given records.
+    pub fn new(records: I) -> Self {
+        Templates { records: records, next: None, allow_incomplete: false }
+    }
+
+    /// Return incomplete templates instead of failing with `TemplateError::Ungrouped`.
+    pub fn allow_incomplete(mut self) -> Self {
+        self.allow_incomplete = true;
+        self
+    }
+}
+
+
+impl<I: Iterator<Item=Result<Record, bam::ReadError>>> Iterator for Templates<I> {
+    type Item = Result<Template, TemplateError>;
+
+    fn next(&mut self) -> Option<Result<Template, TemplateError>> {
+        let mut template = match self.next.take() {
+            Some(record) => Template::new(record),
+            None => match self.records.next() {
+                Some(Ok(record)) => Template::new(record),
+                Some(Err(e))     => return Some(Err(TemplateError::ReadError(e))),
+                None             => return None
+            }
+        };
+        loop {
+            match self.records.next() {
+                Some(Ok(record)) => {
+                    if record.qname() == template.qname() {
+                        template.records.push(record);
+                    } else {
+                        self.next = Some(record);
+                        break;
+                    }
+                },
+                Some(Err(e)) => return Some(Err(TemplateError::ReadError(e))),
+                None         => break
+            }
+        }
+        if self.allow_incomplete || template.is_complete() {
+            Some(Ok(template))
+        } else {
+            Some(Err(TemplateError::Ungrouped(String::from_utf8_lossy(template.qname()).into_owned())))
+        }
+    }
+}
+
+
+/// Iterator over the templates of input that is not grouped by query name, e.g. sorted by
+/// coordinate. Incomplete templates are buffered until all their primary records (and the
+/// supplementary records announced in `SA` tags) have been read. Secondary records are only
+/// included if they occur before the template is complete. The names of the last `max_buffer`
+/// complete templates are remembered, and secondary and supplementary records of them that
+/// occur later are skipped instead of being returned as a template of their own.
+///
+/// If more than the given number of records is buffered, the oldest template is returned
+/// incomplete. The remaining incomplete templates are returned at the end of the input.
+pub struct Collated<I: Iterator<Item=Result<Record, bam::ReadError>>> {
+    records: I,
+    max_buffer: usize,
+    buffer: HashMap<Vec<u8>, Template>,
+    buffered: usize,
+    order: VecDeque<Vec<u8>>,
+    completed: HashSet<Vec<u8>>,
+    completed_order: VecDeque<Vec<u8>>,
+    exhausted: bool,
+}
+
+
+impl<I: Iterator<Item=Result<Record, bam::ReadError>>> Collated<I> {
+    /// Create a new iterator over the templates of the given records.
+    ///
+    /// # Arguments
+    ///
+    /// * `records` - the records to group
+    /// * `max_buffer` - the maximum number of records held back in incomplete templates
+    pub fn new(records: I, max_buffer: usize) -> Self {
+        Collated {
+            records: records,
+            max_buffer: max_buffer,
+            buffer: HashMap::new(),
+            buffered: 0,
+            order: VecDeque::new(),
+            completed: HashSet::new(),
+            completed_order: VecDeque::new(),
+            exhausted: false,
+        }
+    }
+
+    /// Remove the oldest buffered template.
+    fn pop_oldest(&mut self) -> Option<Template> {
+        while let Some(qname) = self.order.pop_front() {
+            if let Some(template) = self.buffer.remove(&qname) {
+                self.buffered -= template.records.len();
+                return Some(template);
+            }
+        }
+        None
+    }
+
+    /// Remember the name of a complete template, such that late records of it can be skipped.
+    fn complete(&mut self, template: Template) -> Template {
+        if self.completed.insert(template.qname().to_owned()) {
+            self.completed_order.push_back(template.qname().to_owned());
+            if self.completed_order.len() > self.max_buffer {
+                let qname = self.completed_order.pop_front().unwrap();
+                self.completed.remove(&qname);
+            }
+        }
+        template
+    }
+}
+
+
+impl<I: Iterator<Item=Result<Record, bam::ReadError>>> Iterator for Collated<I> {
+    type Item = Result<Template, TemplateError>;
+
+    fn next(&mut self) -> Option<Result<Template, TemplateError>> {
+        while !self.exhausted {
+            if self.buffered > self.max_buffer {
+                return self.pop_oldest().map(Ok);
+            }
+            let record = match self.records.next() {
+                Some(Ok(record)) => record,
+                Some(Err(e))     => return Some(Err(TemplateError::ReadError(e))),
+                None             => {
+                    self.exhausted = true;
+                    break;
+                }
+            };
+
+            let qname = record.qname().to_owned();
+            if (record.is_secondary() || record.is_supplementary()) && self.completed.contains(&qname) {
+                continue;
+            }
+            let complete = match self.buffer.get_mut(&qname) {
+                Some(template) => {
+                    template.records.push(record);
+                    template.is_complete() && template.has_supplementary()
+                },
+                None => {
+                    let template = Template::new(record);
+                    if template.is_complete() && template.has_supplementary() {
+                        return Some(Ok(self.complete(template)));
+                    }
+                    self.buffer.insert(qname.clone(), template);
+                    self.order.push_back(qname.clone());
+                    if self.order.len() > 2 * (self.max_buffer + 1) {
+                        // drop names of templates that have already been returned
+                        let buffer = &self.buffer;
+                        self.order.retain(|qname| buffer.contains_key(qname));
+                    }
+                    false
+                }
+            };
+            self.buffered += 1;
+            if complete {
+                let template = self.buffer.remove(&qname).unwrap();
+                self.buffered -= template.records.len();
+                return Some(Ok(self.complete(template)));
+            }
+        }
+        self.pop_oldest().map(Ok)
+    }
+}
+
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum TemplateError {
+        ReadError(err: bam::ReadError) {
+            from()
+        }
+        Ungrouped(qname: String) {
+            description("incomplete template, input is not grouped by query name")
+            display("incomplete template {}, input is not grouped by query name (use collated templates instead)", qname)
+        }
+    }
+}
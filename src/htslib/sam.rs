@@ -11,8 +11,32 @@ extern "C" {
     pub fn bgzf_mt(fp: *mut BGZF, n_threads: ::libc::c_int, n_sub_blks: ::libc::c_int) -> ::libc::c_int;
 }
 
+// leading fields of struct BGZF in htslib 1.3, needed for bgzf_tell
+#[repr(C)]
+pub struct Struct_BGZF_head {
+    pub flags: u32,
+    pub cache_size: ::libc::c_int,
+    pub block_length: ::libc::c_int,
+    pub block_offset: ::libc::c_int,
+    pub block_address: i64,
+    pub uncompressed_address: i64,
+}
+
+/// Virtual file offset of the given BGZF file (a macro in bgzf.h).
+/// Not valid when multi-threading is enabled.
+#[inline]
+pub unsafe fn bgzf_tell(fp: *mut BGZF) -> i64 {
+    let head = &*(fp as *const Struct_BGZF_head);
+    (head.block_address << 16) | (head.block_offset & 0xFFFF) as i64
+}
+
 // hts.h
+pub const HTS_FMT_CSI: ::libc::c_int = 0;
 pub const HTS_FMT_BAI: ::libc::c_int = 1;
+pub const HTS_IDX_NOCOOR: ::libc::c_int = -2;
+pub const HTS_IDX_START: ::libc::c_int = -3;
+pub const HTS_IDX_REST: ::libc::c_int = -4;
+pub const HTS_IDX_NONE: ::libc::c_int = -5;
 
 
 /* automatically generated by rust-bindgen */
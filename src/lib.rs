@@ -32,7 +32,8 @@
 //! let bam = bam::Reader::from_path(&"test/test.bam").unwrap();
 //!
 //! // pileup over all covered sites
-//! for p in bam.pileup() {
+//! let mut pileups = bam.pileup();
+//! while let Some(p) = pileups.next() {
 //!     let pileup = p.unwrap();
 //!     println!("{}:{} depth {}", pileup.tid(), pileup.pos(), pileup.depth());
 //!
@@ -71,6 +72,7 @@ extern crate newtype_derive;
 extern crate custom_derive;
 extern crate url;
 extern crate ieee754;
+extern crate tempdir;
 #[macro_use]
 extern crate lazy_static;
 